@@ -28,4 +28,21 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // ヒープの大きさ(バイト)。指定しなければmemory.xの既定値
+    // feature "alloc"が無効ならヒープを確保しない
+    println!("cargo:rerun-if-env-changed=RRTOS_HEAP_SIZE");
+    let size = if env::var_os("CARGO_FEATURE_ALLOC").is_none() {
+        Some(0)
+    } else if let Ok(size) = env::var("RRTOS_HEAP_SIZE") {
+        let size: usize = size.parse().expect("RRTOS_HEAP_SIZE must be a number");
+        assert!(size > 0, "RRTOS_HEAP_SIZE must not be 0");
+        Some(size)
+    } else {
+        None
+    };
+    let target = env::var("TARGET").unwrap();
+    if let (Some(size), true) = (size, target.starts_with("thumb")) {
+        println!("cargo:rustc-link-arg=--defsym=_rrtos_heap_size={}", size);
+    }
 }
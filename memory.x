@@ -12,4 +12,24 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
+
+/* ### ヒープ(global_allocator) */
+/* 大きさは環境変数 RRTOS_HEAP_SIZE で変えられる(build.rsが --defsym=_rrtos_heap_size で渡す) */
+/* feature "alloc" が無効なら0(build.rsが渡す) */
+_heap_size = DEFINED(_rrtos_heap_size) ? _rrtos_heap_size : 16K;
+
+SECTIONS {
+    .heap (NOLOAD) : ALIGN(8)
+    {
+        __sheap = .;
+        . += _heap_size;
+        . = ALIGN(8);
+        __eheap = .;
+    } > RAM
+} INSERT AFTER .uninit;
+
+ASSERT(__sheap >= __edata && __sheap >= __ebss && __sheap >= __euninit, "
+.heap overlaps .data, .bss or .uninit");
+ASSERT(__eheap <= ORIGIN(RAM) + LENGTH(RAM), "
+.heap does not fit in RAM. Reduce RRTOS_HEAP_SIZE");
@@ -0,0 +1,71 @@
+// バリア
+// n個のタスクが全てwait()に到着するまで待たせ、揃ったら一斉に実行を再開させる。
+// パイプラインの各段のタスクで、周期の開始をそろえるのに使う。
+// 全員が抜けると、同じバリアをもう一度使える。
+//
+// static START: Barrier = Barrier::new(3);
+// loop {
+//     START.wait(); // 3タスクが揃うまで待つ
+//     ...
+// }
+
+use crate::generation::Generation;
+use crate::mutex::Mutex;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    // 最後に到着したタスクだけtrue
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+struct State {
+    generation: Generation,
+    waiters: WaitList,
+}
+
+pub struct Barrier {
+    n: usize,
+    state: Mutex<State>,
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Self {
+        Barrier {
+            n,
+            state: Mutex::new(State {
+                generation: Generation::new(),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    pub fn wait(&self) -> BarrierWaitResult {
+        let generation = {
+            let mut state = self.state.lock();
+            let generation = state.generation.arrive(self.n);
+            if generation.is_none() {
+                state.waiters.wake_all();
+            }
+            generation
+        };
+        let Some(generation) = generation else {
+            return BarrierWaitResult(true);
+        };
+        loop {
+            {
+                let mut state = self.state.lock();
+                state.waiters.remove_current();
+                if state.generation.released(generation) {
+                    return BarrierWaitResult(false);
+                }
+                state.waiters.block_current(None);
+            }
+            wait_list::suspend();
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// ケーパビリティ(アクセス権)
+// タスクはカーネルオブジェクトを、自分のケーパビリティテーブルの番号(CapId)で参照する。
+// 番号が指すケーパビリティに必要な権限(Rights)がなければ、システムコールは失敗する。
+// テーブルにないオブジェクトは、番号を偽造しても参照できない。
+// ケーパビリティはタスクを作ったときに、スケジューラに登録する前にカーネルが与える(取り消す)。
+// IPCはシステムコールで、キュー(restricted()で作ったもの)は操作ごとに、
+// デバイスはドライバがrequire()で確かめる。割り込みハンドラからの操作は確かめない。
+//
+// let server = SCHEDULER.write().push_back(SERVER.init(led_server));
+// let client = CLIENT.init(app_main);
+// let led = client.grant(Object::Task(server), Rights::SEND)?;
+// SCHEDULER.write().push_back(client);
+// // client の中で: ipc::call(led, &msg, &[], &mut [], None)
+
+use core::ops::BitOr;
+use cortex_m::peripheral::scb::VectActive;
+use cortex_m::peripheral::SCB;
+
+use crate::queue::Queue;
+use crate::task::TaskHandle;
+
+pub const CAP_TABLE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rights(u8);
+
+impl Rights {
+    pub const NONE: Rights = Rights(0);
+    pub const SEND: Rights = Rights(1 << 0);
+    pub const RECEIVE: Rights = Rights(1 << 1);
+    pub const ALL: Rights = Rights(0b11);
+
+    pub const fn contains(&self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+    fn bitor(self, rhs: Rights) -> Rights {
+        Rights(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Object {
+    Task(TaskHandle),
+    Queue(usize), // アドレスで識別する
+    Device(u32),  // ペリフェラルなど。番号の意味はアプリケーションで決める
+}
+
+impl Object {
+    pub fn queue<T, const N: usize>(queue: &Queue<T, N>) -> Self {
+        Object::Queue(queue as *const Queue<T, N> as usize)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub object: Object,
+    pub rights: Rights,
+}
+
+// ケーパビリティテーブルの番号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapId(pub u8);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    TableFull,
+    InvalidCap,       // 番号が範囲外か、取り消されている
+    PermissionDenied, // 権限が足りない
+}
+
+pub struct CapabilityTable {
+    caps: [Option<Capability>; CAP_TABLE_LEN],
+}
+
+impl CapabilityTable {
+    pub const fn new() -> Self {
+        CapabilityTable {
+            caps: [None; CAP_TABLE_LEN],
+        }
+    }
+
+    // 空いている番号に登録する。同じオブジェクトが登録済みなら権限を追加する
+    pub fn grant(&mut self, object: Object, rights: Rights) -> Result<CapId, Error> {
+        if let Some(i) = self.find(object) {
+            let cap = self.caps[i].as_mut().unwrap();
+            cap.rights = cap.rights | rights;
+            return Ok(CapId(i as u8));
+        }
+        let i = self
+            .caps
+            .iter()
+            .position(|c| c.is_none())
+            .ok_or(Error::TableFull)?;
+        self.caps[i] = Some(Capability { object, rights });
+        Ok(CapId(i as u8))
+    }
+
+    pub fn revoke(&mut self, id: CapId) -> Result<Capability, Error> {
+        self.caps
+            .get_mut(id.0 as usize)
+            .and_then(Option::take)
+            .ok_or(Error::InvalidCap)
+    }
+
+    pub fn get(&self, id: CapId) -> Option<Capability> {
+        self.caps.get(id.0 as usize).copied().flatten()
+    }
+
+    // システムコールで使う。idがrightsを全て持っていれば、指しているオブジェクトを返す
+    pub fn check(&self, id: CapId, rights: Rights) -> Result<Object, Error> {
+        let cap = self.get(id).ok_or(Error::InvalidCap)?;
+        if !cap.rights.contains(rights) {
+            return Err(Error::PermissionDenied);
+        }
+        Ok(cap.object)
+    }
+
+    // 番号を使わずにオブジェクトで確かめる。キューやデバイスの操作で使う
+    pub fn check_object(&self, object: Object, rights: Rights) -> Result<(), Error> {
+        let i = self.find(object).ok_or(Error::PermissionDenied)?;
+        match self.caps[i] {
+            Some(cap) if cap.rights.contains(rights) => Ok(()),
+            _ => Err(Error::PermissionDenied),
+        }
+    }
+
+    fn find(&self, object: Object) -> Option<usize> {
+        self.caps
+            .iter()
+            .position(|c| c.is_some_and(|c| c.object == object))
+    }
+}
+
+impl Default for CapabilityTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 実行中のタスクがobjectをrightsで使えるか確かめる。割り込みハンドラとカーネルはいつでも使える
+//
+// if capability::require(Object::Device(UART0), Rights::SEND).is_err() { ... }
+pub fn require(object: Object, rights: Rights) -> Result<(), Error> {
+    if SCB::vect_active() != VectActive::ThreadMode {
+        return Ok(());
+    }
+    match TaskHandle::current() {
+        Some(task) => task.capabilities().check_object(object, rights),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CapId, CapabilityTable, Error, Object, Rights, CAP_TABLE_LEN};
+
+    #[test]
+    fn test_grant_check_revoke() {
+        let mut table = CapabilityTable::new();
+        let uart = table.grant(Object::Device(1), Rights::SEND).unwrap();
+        let adc = table.grant(Object::Device(2), Rights::RECEIVE).unwrap();
+        assert_ne!(uart, adc);
+
+        assert!(table.check(uart, Rights::SEND) == Ok(Object::Device(1)));
+        assert_eq!(
+            Err(Error::PermissionDenied),
+            table
+                .check(uart, Rights::SEND | Rights::RECEIVE)
+                .map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::InvalidCap),
+            table
+                .check(CapId(CAP_TABLE_LEN as u8), Rights::NONE)
+                .map(|_| ())
+        );
+
+        // 同じオブジェクトは同じ番号のまま権限を追加する
+        assert_eq!(Ok(uart), table.grant(Object::Device(1), Rights::RECEIVE));
+        assert!(table.check(uart, Rights::SEND | Rights::RECEIVE).is_ok());
+
+        assert!(table.revoke(uart).is_ok());
+        assert_eq!(
+            Err(Error::InvalidCap),
+            table.check(uart, Rights::SEND).map(|_| ())
+        );
+        assert!(table.revoke(uart).is_err());
+        assert!(table.check(adc, Rights::RECEIVE).is_ok());
+    }
+
+    #[test]
+    fn test_check_object() {
+        let mut table = CapabilityTable::new();
+        table.grant(Object::Queue(0x100), Rights::SEND).unwrap();
+        table
+            .grant(Object::Queue(0x300), Rights::SEND | Rights::RECEIVE)
+            .unwrap();
+        assert_eq!(
+            Ok(()),
+            table.check_object(Object::Queue(0x100), Rights::SEND)
+        );
+        assert_eq!(
+            Err(Error::PermissionDenied),
+            table.check_object(Object::Queue(0x100), Rights::RECEIVE)
+        );
+        assert_eq!(
+            Ok(()),
+            table.check_object(Object::Queue(0x300), Rights::RECEIVE)
+        );
+        // 持っていないオブジェクト
+        assert_eq!(
+            Err(Error::PermissionDenied),
+            table.check_object(Object::Queue(0x200), Rights::NONE)
+        );
+        assert_eq!(
+            Err(Error::PermissionDenied),
+            table.check_object(Object::Device(1), Rights::NONE)
+        );
+    }
+
+    #[test]
+    fn test_table_full() {
+        let mut table = CapabilityTable::new();
+        for i in 0..CAP_TABLE_LEN as u32 {
+            table.grant(Object::Device(i), Rights::ALL).unwrap();
+        }
+        assert_eq!(
+            Err(Error::TableFull),
+            table.grant(Object::Device(100), Rights::ALL)
+        );
+        table.revoke(CapId(3)).unwrap();
+        assert_eq!(Ok(CapId(3)), table.grant(Object::Device(100), Rights::ALL));
+    }
+}
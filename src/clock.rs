@@ -0,0 +1,169 @@
+// RP2040のTIMER(1MHzで動き続ける64bitカウンタ)
+// Instant::now()でマイクロ秒単位の時刻を読む。割り込みの遅延の計測や短い待ちに使う。
+// ALARM0はカーネルの時間の基準(systick::init_timer)にも使える。
+//
+// clock::init(pac.TIMER, &mut pac.RESETS);
+// let start = Instant::now();
+// ...
+// info!("{}us", start.elapsed().as_micros());
+
+use core::ops::{Add, Sub};
+use core::time::Duration;
+use rp2040_hal::pac;
+
+// 起動(init)してからのマイクロ秒
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        let timer = unsafe { &*pac::TIMER::PTR };
+        Instant(read_counter(
+            || timer.timerawh().read().bits(),
+            || timer.timerawl().read().bits(),
+        ))
+    }
+
+    pub const fn from_micros(us: u64) -> Self {
+        Instant(us)
+    }
+
+    pub const fn as_micros(&self) -> u64 {
+        self.0
+    }
+
+    // earlierより前なら0
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.as_micros() as u64)
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs.as_micros() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+// TIMERのリセットを解除してカウントを始める。Instantを使う前に1度だけ呼ぶ
+pub fn init(_timer: pac::TIMER, resets: &mut pac::RESETS) {
+    resets.reset().modify(|_, w| w.timer().set_bit());
+    resets.reset().modify(|_, w| w.timer().clear_bit());
+    while resets.reset_done().read().timer().bit_is_clear() {}
+}
+
+// 上位と下位を別々に読むので、間で下位が桁上がりしたら読み直す
+fn read_counter(mut hi: impl FnMut() -> u32, mut lo: impl FnMut() -> u32) -> u64 {
+    let mut hi0 = hi();
+    loop {
+        let low = lo();
+        let hi1 = hi();
+        if hi0 == hi1 {
+            return ((hi0 as u64) << 32) | low as u64;
+        }
+        hi0 = hi1;
+    }
+}
+
+// 下位32bit。アラームの比較はこの値で行われる
+pub fn now_low() -> u32 {
+    unsafe { &*pac::TIMER::PTR }.timerawl().read().bits()
+}
+
+// 下位32bitがatになるとTIMER_IRQ_0を発生させる。
+// 既に過ぎていれば一致するのは約71分後なので、すぐに発生させる
+pub(crate) fn set_alarm(at: u32) {
+    let timer = unsafe { &*pac::TIMER::PTR };
+    timer.inte().modify(|_, w| w.alarm_0().set_bit());
+    timer.alarm0().write(|w| unsafe { w.bits(at) });
+    if now_low().wrapping_sub(at) as i32 >= 0 {
+        timer.intf().modify(|_, w| w.alarm_0().set_bit());
+    }
+}
+
+// TIMERをusマイクロ秒進める。TIMERが止まっていた(Dormant)間の時間を足す
+pub(crate) fn advance(us: u64) {
+    if us == 0 {
+        return;
+    }
+    let timer = unsafe { &*pac::TIMER::PTR };
+    let now = Instant::now().as_micros() + us;
+    // TIMELWはTIMEHWを書いたときに一緒に反映される
+    timer.timelw().write(|w| unsafe { w.bits(now as u32) });
+    timer
+        .timehw()
+        .write(|w| unsafe { w.bits((now >> 32) as u32) });
+}
+
+// TIMER_IRQ_0の中で、割り込みを受け付けたことを知らせる
+pub(crate) fn clear_alarm() {
+    let timer = unsafe { &*pac::TIMER::PTR };
+    timer.intf().modify(|_, w| w.alarm_0().clear_bit());
+    timer.intr().write(|w| w.alarm_0().clear_bit_by_one());
+}
+
+// usマイクロ秒の間、待つ(ビジーループ)。タスクを切り替えないので、短い待ちに使う
+pub fn delay_us(us: u32) {
+    let start = now_low();
+    while now_low().wrapping_sub(start) < us {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_counter, Instant};
+    use core::cell::Cell;
+    use core::time::Duration;
+
+    #[test]
+    fn test_read_counter_carry() {
+        // 下位を読んだ直後に桁上がりした
+        let hi = Cell::new(0);
+        let reads = Cell::new(0);
+        let value = read_counter(
+            || hi.get(),
+            || {
+                reads.set(reads.get() + 1);
+                if reads.get() == 1 {
+                    hi.set(1);
+                    u32::MAX
+                } else {
+                    5
+                }
+            },
+        );
+        assert_eq!((1 << 32) | 5, value);
+        assert_eq!(2, reads.get());
+        assert_eq!(0x1234_5678_9abc, read_counter(|| 0x1234, || 0x5678_9abc));
+    }
+
+    #[test]
+    fn test_instant_arithmetic() {
+        let t = Instant::from_micros(1_000);
+        let later = t + Duration::from_millis(2);
+        assert_eq!(3_000, later.as_micros());
+        assert_eq!(Duration::from_micros(2_000), later - t);
+        assert_eq!(Duration::ZERO, t.duration_since(later));
+        assert_eq!(t, later - Duration::from_micros(2_000));
+        assert!(t < later);
+    }
+}
@@ -0,0 +1,96 @@
+// 条件変数
+// mutex::Mutexと組み合わせて、条件が成立するまでスピンせずにタスクを待たせる。
+// std::sync::Condvarと同じく、起床は偽(spurious)の場合があるので条件はループで確認する
+//
+// let mut ready = READY.lock();
+// while !*ready {
+//     ready = CONDVAR.wait(ready);
+// }
+
+use crate::mutex::{Mutex, MutexGuard};
+use crate::systick;
+use crate::tick;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+pub struct Condvar {
+    waiters: Mutex<WaitList>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: Mutex::new(WaitList::new()),
+        }
+    }
+
+    // guardのロックを開放して通知を待ち、ロックを取り直して返す
+    pub fn wait<'a, T, const L: u8>(&self, guard: MutexGuard<'a, T, L>) -> MutexGuard<'a, T, L> {
+        self.wait_until(guard, None).0
+    }
+
+    // timeoutはtick数
+    pub fn wait_timeout<'a, T, const L: u8>(
+        &self,
+        guard: MutexGuard<'a, T, L>,
+        timeout: u32,
+    ) -> (MutexGuard<'a, T, L>, WaitTimeoutResult) {
+        self.wait_until(guard, Some(systick::deadline(timeout)))
+    }
+
+    // conditionがtrueの間待つ
+    pub fn wait_while<'a, T, F, const L: u8>(
+        &self,
+        mut guard: MutexGuard<'a, T, L>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T, L>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.lock().wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.lock().wake_all();
+    }
+
+    fn wait_until<'a, T, const L: u8>(
+        &self,
+        guard: MutexGuard<'a, T, L>,
+        deadline: Option<u32>,
+    ) -> (MutexGuard<'a, T, L>, WaitTimeoutResult) {
+        let mutex = MutexGuard::mutex(&guard);
+        // Mutexを開放する前に待ち状態になっておく。開放直後に通知されても起床は失われない
+        self.waiters.lock().block_current(deadline);
+        drop(guard);
+        wait_list::suspend();
+        let guard = mutex.lock();
+
+        // notify_*()以外(タイムアウトや他の通知)で起こされた場合は、自分で待ちリストから削除する
+        self.waiters.lock().remove_current();
+        // 期限を過ぎていればタイムアウト。期限の前に起こされたなら、notify_*()でなくてもタイムアウトではない
+        let timed_out = tick::timed_out(deadline, systick::count_get());
+        (guard, WaitTimeoutResult(timed_out))
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,179 @@
+// カーネルのクリティカルセクション
+// 割り込み禁止(PRIMASK)で同じコアの割り込みを、ハードウェアスピンロック31でもう一方のコアを排除する。
+// 割り込みハンドラと共有するデータはmutex::Mutexではなく、これを使ったIrqMutexで保護する。
+// (mutex::Mutexをタスクが保持している間に割り込みハンドラがlockすると、永久にスピンする)
+//
+// タスクは非特権モードで動いているので、そのままでは割り込みを禁止できない(cpsidが無視される)。
+// タスクから呼ばれた場合はシステムコールで特権モードに上げると同時に割り込みを禁止し、
+// 開放するときに非特権モードに戻す。
+// これはタスクを保護する仕組みではない。acquire()はどのタスクも呼べて、release()を呼ばなければ
+// 特権モードのまま動ける。ケーパビリティやIPCのアドレスの確認も、タスクが信頼できることを前提にしている。
+// PRIMASKがセットされている間にsvcを実行するとHardFaultになるので、
+// クリティカルセクションの中で syscall::back_to_kernel() などを呼んではいけない。
+// 割り込みハンドラは常に特権モードで動く(CONTROL.nPRIVはスレッドモードにしか効かない)。
+
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{compiler_fence, AtomicU8, Ordering};
+use cortex_m::peripheral::scb::VectActive;
+use cortex_m::peripheral::SCB;
+use cortex_m::register::{control, primask};
+use rp2040_hal::sio::Spinlock31;
+use rp2040_hal::Sio;
+
+use crate::syscall;
+
+// LOCK_OWNERの値: 0 = 誰も持っていない、1 = core0、2 = core1
+const LOCK_UNOWNED: u8 = 0;
+static LOCK_OWNER: AtomicU8 = AtomicU8::new(LOCK_UNOWNED);
+
+// RestoreStateのビット
+const IRQ_WAS_ENABLED: u8 = 1 << 0;
+const WAS_UNPRIVILEGED: u8 = 1 << 1;
+const ALREADY_OWNED: u8 = 1 << 2; // ネストしている。スピンロックは外側が開放する
+
+// acquire()の戻り値。release()に渡して元の状態に戻す
+#[derive(Clone, Copy)]
+pub struct RestoreState(u8);
+
+// クリティカルセクションの中にいることを示すトークン
+#[derive(Clone, Copy)]
+pub struct CriticalSection<'cs> {
+    marker: PhantomData<&'cs ()>,
+}
+
+/// クリティカルセクションに入る。ネストしてもよい
+///
+/// # Safety
+/// 戻り値を、ネストの逆順でrelease()に渡すこと
+pub unsafe fn acquire() -> RestoreState {
+    let mut state = 0;
+    // 非特権モードでもPRIMASKは読める
+    if primask::read().is_active() {
+        state |= IRQ_WAS_ENABLED;
+    }
+    if SCB::vect_active() == VectActive::ThreadMode
+        && control::read().npriv() == control::Npriv::Unprivileged
+    {
+        // 戻ってきたときには割り込みが禁止されている
+        syscall::enter_privileged();
+        state |= WAS_UNPRIVILEGED;
+    }
+    let core = Sio::core() as u8 + 1;
+    if LOCK_OWNER.load(Ordering::Acquire) == core {
+        // 同じコアで既にクリティカルセクションに入っている(割り込みも禁止済み)
+        return RestoreState(state | ALREADY_OWNED);
+    }
+    loop {
+        cortex_m::interrupt::disable();
+        compiler_fence(Ordering::SeqCst);
+        if let Some(lock) = Spinlock31::try_claim() {
+            // release()で開放するまでスピンロックを保持する
+            core::mem::forget(lock);
+            LOCK_OWNER.store(core, Ordering::Relaxed);
+            break;
+        }
+        // もう一方のコアが保持している。待っている間は割り込みを受け付ける
+        if state & IRQ_WAS_ENABLED != 0 {
+            cortex_m::interrupt::enable();
+        }
+    }
+    RestoreState(state)
+}
+
+/// クリティカルセクションから出る
+///
+/// # Safety
+/// 対応するacquire()の戻り値を渡すこと
+pub unsafe fn release(state: RestoreState) {
+    let RestoreState(state) = state;
+    if state & ALREADY_OWNED == 0 {
+        LOCK_OWNER.store(LOCK_UNOWNED, Ordering::Relaxed);
+        compiler_fence(Ordering::SeqCst);
+        Spinlock31::release();
+    }
+    if state & IRQ_WAS_ENABLED != 0 {
+        cortex_m::interrupt::enable();
+    }
+    if state & WAS_UNPRIVILEGED != 0 {
+        // 特権モードのスレッドは自分で非特権モードに戻れる
+        let mut ctrl = control::read();
+        ctrl.set_npriv(control::Npriv::Unprivileged);
+        control::write(ctrl);
+    }
+}
+
+pub fn with<R>(f: impl FnOnce(CriticalSection) -> R) -> R {
+    let state = unsafe { acquire() };
+    let result = f(CriticalSection {
+        marker: PhantomData,
+    });
+    unsafe { release(state) };
+    result
+}
+
+// critical-sectionクレートの実装を提供する(defmt-rttなどが使う)
+struct KernelCriticalSection;
+::critical_section::set_impl!(KernelCriticalSection);
+
+unsafe impl ::critical_section::Impl for KernelCriticalSection {
+    unsafe fn acquire() -> ::critical_section::RawRestoreState {
+        acquire().0
+    }
+
+    unsafe fn release(state: ::critical_section::RawRestoreState) {
+        release(RestoreState(state));
+    }
+}
+
+// 割り込みハンドラと共有するデータのためのロック
+// lock()している間はクリティカルセクションなので、割り込みハンドラからも待たずにロックできる
+pub struct IrqMutex<T> {
+    locked: Cell<bool>,
+    data: UnsafeCell<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    lock: &'a IrqMutex<T>,
+    state: RestoreState,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            locked: Cell::new(false),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let state = unsafe { acquire() };
+        // クリティカルセクションの中なので、同じロックを取ろうとするのは自分自身だけ
+        assert!(!self.locked.get(), "IrqMutex is locked recursively");
+        self.locked.set(true);
+        IrqMutexGuard { lock: self, state }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.set(false);
+        unsafe { release(self.state) };
+    }
+}
+
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
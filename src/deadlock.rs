@@ -0,0 +1,212 @@
+// Mutexのデッドロック検出(デバッグ用、feature = "deadlock-detection")
+// どのタスクがどのロックを保持し、どのロックを待っているか(wait-forグラフ)を記録する。
+// ロックを待ち始めるときにグラフをたどり、自分に戻ってくる(サイクルがある)とデッドロック。
+// 関係するタスクとロックのアドレスをdefmtで出力してから停止する。
+//
+// タスクはTaskHandle::id()、カーネル(タスク以外)は0で表す。
+// featureが無効なときは何もしない。
+
+#[cfg(any(test, feature = "deadlock-detection"))]
+const MAX_LOCKS: usize = 16;
+#[cfg(any(test, feature = "deadlock-detection"))]
+const MAX_TASKS: usize = 16;
+
+// デッドロックしたときの(タスク, そのタスクが待っているロック)の列
+#[cfg(any(test, feature = "deadlock-detection"))]
+struct Cycle {
+    edges: [(usize, usize); MAX_TASKS],
+    len: usize,
+}
+
+#[cfg(any(test, feature = "deadlock-detection"))]
+struct WaitForGraph {
+    holders: [Option<(usize, usize)>; MAX_LOCKS], // (lock, 保持しているtask)
+    waiting: [Option<(usize, usize)>; MAX_TASKS], // (task, 待っているlock)
+}
+
+#[cfg(any(test, feature = "deadlock-detection"))]
+impl WaitForGraph {
+    const fn new() -> Self {
+        WaitForGraph {
+            holders: [None; MAX_LOCKS],
+            waiting: [None; MAX_TASKS],
+        }
+    }
+
+    fn holder(&self, lock: usize) -> Option<usize> {
+        self.holders
+            .iter()
+            .flatten()
+            .find(|(l, _)| *l == lock)
+            .map(|(_, t)| *t)
+    }
+
+    fn waiting_on(&self, task: usize) -> Option<usize> {
+        self.waiting
+            .iter()
+            .flatten()
+            .find(|(t, _)| *t == task)
+            .map(|(_, l)| *l)
+    }
+
+    // 表がいっぱいなら記録しない(検出漏れになるだけ)
+    fn insert(table: &mut [Option<(usize, usize)>], key: usize, value: usize) {
+        if let Some(entry) = table.iter_mut().find(|e| e.is_none_or(|(k, _)| k == key)) {
+            *entry = Some((key, value));
+        }
+    }
+
+    fn remove(table: &mut [Option<(usize, usize)>], key: usize) {
+        for entry in table.iter_mut() {
+            if entry.is_some_and(|(k, _)| k == key) {
+                *entry = None;
+            }
+        }
+    }
+
+    fn wait(&mut self, task: usize, lock: usize) {
+        Self::insert(&mut self.waiting, task, lock);
+    }
+
+    fn acquired(&mut self, task: usize, lock: usize) {
+        Self::remove(&mut self.waiting, task);
+        Self::insert(&mut self.holders, lock, task);
+    }
+
+    fn released(&mut self, lock: usize) {
+        Self::remove(&mut self.holders, lock);
+    }
+
+    // taskがlockを待つとサイクルになるか
+    fn find_cycle(&self, task: usize, lock: usize) -> Option<Cycle> {
+        let mut cycle = Cycle {
+            edges: [(0, 0); MAX_TASKS],
+            len: 0,
+        };
+        let (mut t, mut l) = (task, lock);
+        while cycle.len < MAX_TASKS {
+            cycle.edges[cycle.len] = (t, l);
+            cycle.len += 1;
+            t = self.holder(l)?;
+            if t == task {
+                return Some(cycle);
+            }
+            l = self.waiting_on(t)?;
+        }
+        None
+    }
+}
+
+#[cfg(feature = "deadlock-detection")]
+mod detect {
+    use super::WaitForGraph;
+    use crate::critical_section::IrqMutex;
+    use crate::task::TaskHandle;
+    use defmt::error;
+
+    static GRAPH: IrqMutex<WaitForGraph> = IrqMutex::new(WaitForGraph::new());
+
+    fn current() -> usize {
+        TaskHandle::current().map_or(0, |t| t.id())
+    }
+
+    pub(crate) fn wait(lock: usize) {
+        let me = current();
+        let mut graph = GRAPH.lock();
+        if let Some(cycle) = graph.find_cycle(me, lock) {
+            drop(graph);
+            error!("deadlock detected");
+            for &(task, lock) in &cycle.edges[..cycle.len] {
+                error!("  task {:x} waits for lock {:x}", task, lock);
+            }
+            panic!("deadlock");
+        }
+        graph.wait(me, lock);
+    }
+
+    pub(crate) fn acquired(lock: usize) {
+        let me = current();
+        GRAPH.lock().acquired(me, lock);
+    }
+
+    pub(crate) fn released(lock: usize) {
+        GRAPH.lock().released(lock);
+    }
+}
+
+#[cfg(feature = "deadlock-detection")]
+pub(crate) use detect::{acquired, released, wait};
+
+#[cfg(not(feature = "deadlock-detection"))]
+pub(crate) fn wait(_lock: usize) {}
+
+#[cfg(not(feature = "deadlock-detection"))]
+pub(crate) fn acquired(_lock: usize) {}
+
+#[cfg(not(feature = "deadlock-detection"))]
+pub(crate) fn released(_lock: usize) {}
+
+#[cfg(test)]
+mod test {
+    use super::WaitForGraph;
+
+    #[test]
+    fn test_no_cycle() {
+        let mut graph = WaitForGraph::new();
+        graph.acquired(1, 0x100);
+        assert!(graph.find_cycle(2, 0x100).is_none());
+        graph.wait(2, 0x100);
+        // task 1 は何も待っていない
+        assert!(graph.find_cycle(3, 0x100).is_none());
+        // 誰も保持していない
+        assert!(graph.find_cycle(1, 0x200).is_none());
+    }
+
+    #[test]
+    fn test_two_tasks_opposite_order() {
+        let mut graph = WaitForGraph::new();
+        graph.acquired(1, 0x100);
+        graph.acquired(2, 0x200);
+        graph.wait(1, 0x200);
+        let cycle = graph.find_cycle(2, 0x100).unwrap();
+        assert_eq!(&[(2, 0x100), (1, 0x200)], &cycle.edges[..cycle.len]);
+    }
+
+    #[test]
+    fn test_self_deadlock() {
+        let mut graph = WaitForGraph::new();
+        graph.acquired(1, 0x100);
+        let cycle = graph.find_cycle(1, 0x100).unwrap();
+        assert_eq!(&[(1, 0x100)], &cycle.edges[..cycle.len]);
+    }
+
+    #[test]
+    fn test_released() {
+        let mut graph = WaitForGraph::new();
+        graph.acquired(1, 0x100);
+        graph.acquired(2, 0x200);
+        graph.wait(1, 0x200);
+        graph.released(0x100);
+        assert!(graph.find_cycle(2, 0x100).is_none());
+        // 待っていたロックを取ると待ちは消える
+        graph.released(0x200);
+        graph.acquired(1, 0x200);
+        assert_eq!(None, graph.waiting_on(1));
+        assert_eq!(Some(1), graph.holder(0x200));
+    }
+
+    #[test]
+    fn test_three_tasks() {
+        let mut graph = WaitForGraph::new();
+        graph.acquired(1, 0x100);
+        graph.acquired(2, 0x200);
+        graph.acquired(3, 0x300);
+        graph.wait(1, 0x200);
+        graph.wait(2, 0x300);
+        let cycle = graph.find_cycle(3, 0x100).unwrap();
+        assert_eq!(
+            &[(3, 0x100), (1, 0x200), (2, 0x300)],
+            &cycle.edges[..cycle.len]
+        );
+    }
+}
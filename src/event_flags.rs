@@ -0,0 +1,90 @@
+// イベントフラグの値と、待ち条件の判定。EventGroupの中身
+// 待ちリストは持たないので、単体でテストできる
+//
+// ❯ rustc --test src/event_flags.rs
+
+pub struct EventFlags {
+    bits: u32,
+}
+
+impl EventFlags {
+    pub const fn new() -> Self {
+        EventFlags { bits: 0 }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.bits
+    }
+
+    // セット後の値を返す
+    pub fn set(&mut self, bits: u32) -> u32 {
+        self.bits |= bits;
+        self.bits
+    }
+
+    // クリア前の値を返す
+    pub fn clear(&mut self, bits: u32) -> u32 {
+        let prev = self.bits;
+        self.bits &= !bits;
+        prev
+    }
+
+    // 条件が成立していれば、その時点の値を返す。clear_on_exitならbitsをクリアする
+    // allなら全部、そうでなければどれかがセットされていれば成立
+    pub fn take(&mut self, bits: u32, all: bool, clear_on_exit: bool) -> Option<u32> {
+        let value = self.bits;
+        if !satisfied(value, bits, all) {
+            return None;
+        }
+        if clear_on_exit {
+            self.bits &= !bits;
+        }
+        Some(value)
+    }
+}
+
+impl Default for EventFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn satisfied(value: u32, bits: u32, all: bool) -> bool {
+    if all {
+        value & bits == bits
+    } else {
+        value & bits != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{satisfied, EventFlags};
+
+    #[test]
+    fn test_satisfied() {
+        assert!(satisfied(0b0110, 0b0010, false));
+        assert!(!satisfied(0b0110, 0b1001, false));
+        assert!(satisfied(0b0110, 0b0110, true));
+        assert!(!satisfied(0b0110, 0b0111, true));
+        // 0ビットは、全部なら常に成立し、どれかなら成立しない
+        assert!(satisfied(0, 0, true));
+        assert!(!satisfied(u32::MAX, 0, false));
+    }
+
+    #[test]
+    fn test_take() {
+        let mut flags = EventFlags::new();
+        assert_eq!(0b0011, flags.set(0b0011));
+        assert_eq!(None, flags.take(0b0111, true, true));
+        // 成立しなければクリアしない
+        assert_eq!(0b0011, flags.get());
+        assert_eq!(Some(0b0011), flags.take(0b0110, false, false));
+        assert_eq!(0b0011, flags.get());
+        // 成立した時点の値を返し、待っていたビットだけクリアする
+        assert_eq!(Some(0b0011), flags.take(0b0001, true, true));
+        assert_eq!(0b0010, flags.get());
+        assert_eq!(0b0010, flags.clear(0b0010));
+        assert_eq!(None, flags.take(0b0010, false, true));
+    }
+}
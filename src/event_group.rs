@@ -0,0 +1,119 @@
+// イベントフラグ(32bit)
+// 複数の条件の組み合わせ(どれか/全部)が成立するまでタスクを待たせる
+//
+// static EVENTS: EventGroup = EventGroup::new();
+// EVENTS.set(RX_READY);                                   // 割り込みハンドラからも呼べる
+// EVENTS.wait_all(RX_READY | CONFIG_LOADED, true, Some(100))?; // 成立したらビットをクリアする
+
+use crate::critical_section::IrqMutex;
+use crate::event_flags::EventFlags;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    NoBits, // wait_anyのbitsが0(いつまでも成立しない)
+}
+
+struct Inner {
+    flags: EventFlags,
+    waiters: WaitList,
+}
+
+pub struct EventGroup {
+    inner: IrqMutex<Inner>,
+}
+
+impl EventGroup {
+    pub const fn new() -> Self {
+        EventGroup {
+            inner: IrqMutex::new(Inner {
+                flags: EventFlags::new(),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> u32 {
+        self.inner.lock().flags.get()
+    }
+
+    // ビットをセットして、待っているタスクを全て起こす(各タスクが自分の条件を確認する)
+    // セット後の値を返す
+    pub fn set(&self, bits: u32) -> u32 {
+        let mut inner = self.inner.lock();
+        let value = inner.flags.set(bits);
+        inner.waiters.wake_all();
+        value
+    }
+
+    // ビットをクリアして、クリア前の値を返す
+    pub fn clear(&self, bits: u32) -> u32 {
+        self.inner.lock().flags.clear(bits)
+    }
+
+    // set/clearはクリティカルセクションで保護しているので、割り込みハンドラからも呼べる
+    pub fn set_from_isr(&self, bits: u32) -> u32 {
+        self.set(bits)
+    }
+
+    pub fn clear_from_isr(&self, bits: u32) -> u32 {
+        self.clear(bits)
+    }
+
+    // bitsのどれかがセットされるまで待つ
+    // 条件が成立した時点の値を返す。clear_on_exitならbitsをクリアする
+    // bitsが0ならErr(NoBits)
+    pub fn wait_any(
+        &self,
+        bits: u32,
+        clear_on_exit: bool,
+        timeout: Option<u32>,
+    ) -> Result<u32, Error> {
+        self.wait(bits, false, clear_on_exit, timeout)
+    }
+
+    // bitsの全てがセットされるまで待つ。bitsが0ならすぐに返す
+    pub fn wait_all(
+        &self,
+        bits: u32,
+        clear_on_exit: bool,
+        timeout: Option<u32>,
+    ) -> Result<u32, Error> {
+        self.wait(bits, true, clear_on_exit, timeout)
+    }
+
+    fn wait(
+        &self,
+        bits: u32,
+        all: bool,
+        clear_on_exit: bool,
+        timeout: Option<u32>,
+    ) -> Result<u32, Error> {
+        if !all && bits == 0 {
+            return Err(Error::NoBits);
+        }
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.waiters.remove_current();
+                if let Some(value) = inner.flags.take(bits, all, clear_on_exit) {
+                    return Ok(value);
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(Error::Timeout);
+                }
+                inner.waiters.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+}
+
+impl Default for EventGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
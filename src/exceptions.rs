@@ -1,12 +1,21 @@
+use crate::syscall;
 use core::arch::asm;
 use cortex_m::peripheral::SCB;
-use cortex_m_rt::exception;
+use cortex_m::register::{control, psp};
+use cortex_m_rt::{exception, ExceptionFrame};
 
 // SVCall hander
-// PendSVをセットする⇒全ての割り込みを処理したあとPendSV handlerが呼ばれる
+// カーネル(特権モード)から呼ばれたら⇒PendSVをセットする⇒全ての割り込みを処理したあとPendSV handlerが呼ばれる
+// タスク(非特権モード)から呼ばれたら⇒PSPに積まれたr0のシステムコール番号で処理を振り分ける
+// CONTROL.nPRIVは例外に入っても変わらないので、呼び出し元の特権レベルを示している
 #[exception]
 fn SVCall() {
-    SCB::set_pendsv();
+    if control::read().npriv() == control::Npriv::Privileged {
+        SCB::set_pendsv();
+        return;
+    }
+    let frame = unsafe { &mut *(psp::read() as *mut ExceptionFrame) };
+    syscall::dispatch(frame);
 }
 
 // PendSV handler
@@ -0,0 +1,147 @@
+// 固定長のリングバッファ(FIFO)。Queueの中身
+// ロックや待ちリストは持たないので、単体でテストできる
+//
+// ❯ rustc --test src/fifo.rs
+
+use core::mem::MaybeUninit;
+
+pub struct Fifo<T, const N: usize> {
+    buf: [MaybeUninit<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Fifo<T, N> {
+    pub const fn new() -> Self {
+        Fifo {
+            buf: [const { MaybeUninit::uninit() }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    // いっぱいなら値を返す
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.buf[(self.head + self.len) % N].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = unsafe { self.buf[self.head].assume_init_read() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for Fifo<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Fifo<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::Fifo;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_fifo() {
+        let mut fifo: Fifo<u32, 3> = Fifo::new();
+        assert_eq!(None, fifo.pop());
+
+        assert_eq!(Ok(()), fifo.push(1));
+        assert_eq!(Ok(()), fifo.push(2));
+        assert_eq!(Ok(()), fifo.push(3));
+        assert!(fifo.is_full());
+        assert_eq!(Err(4), fifo.push(4));
+
+        assert_eq!(Some(1), fifo.pop());
+        assert_eq!(Ok(()), fifo.push(4));
+        assert_eq!(Some(2), fifo.pop());
+        assert_eq!(Some(3), fifo.pop());
+        assert_eq!(Some(4), fifo.pop());
+        assert_eq!(None, fifo.pop());
+        assert!(fifo.is_empty());
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let mut fifo: Fifo<u32, 2> = Fifo::new();
+        for i in 0..10 {
+            assert_eq!(Ok(()), fifo.push(i));
+            assert_eq!(Some(i), fifo.pop());
+        }
+        assert_eq!(0, fifo.len());
+    }
+
+    #[test]
+    fn test_drop() {
+        use std::cell::Cell;
+
+        struct Counted<'a>(&'a Cell<u32>);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let dropped = Cell::new(0);
+        {
+            let mut fifo: Fifo<Counted, 4> = Fifo::new();
+            let _ = fifo.push(Counted(&dropped));
+            let _ = fifo.push(Counted(&dropped));
+            drop(fifo.pop());
+            assert_eq!(1, dropped.get());
+        }
+        assert_eq!(2, dropped.get());
+    }
+
+    // VecDequeと同じ結果になるか、疑似乱数の操作列で確かめる
+    #[test]
+    fn test_against_vec_deque() {
+        let mut fifo: Fifo<u32, 5> = Fifo::new();
+        let mut model = VecDeque::new();
+        let mut seed = 1u32;
+        for i in 0..10_000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            if seed.is_multiple_of(3) {
+                assert_eq!(model.pop_front(), fifo.pop());
+            } else if model.len() < 5 {
+                model.push_back(i);
+                assert_eq!(Ok(()), fifo.push(i));
+            } else {
+                assert_eq!(Err(i), fifo.push(i));
+            }
+            assert_eq!(model.len(), fifo.len());
+        }
+    }
+}
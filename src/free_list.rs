@@ -0,0 +1,122 @@
+// 空きブロックの番号をつないだスタック。Poolの中身
+// ブロックや待ちリストは持たないので、単体でテストできる
+//
+// ❯ rustc --test src/free_list.rs
+
+const NIL: usize = usize::MAX;
+
+pub struct FreeList<const N: usize> {
+    next: [usize; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> FreeList<N> {
+    // 最初は全部空いている
+    pub const fn new() -> Self {
+        let mut next = [NIL; N];
+        let mut i = 0;
+        while i + 1 < N {
+            next[i] = i + 1;
+            i += 1;
+        }
+        FreeList {
+            next,
+            head: if N == 0 { NIL } else { 0 },
+            len: N,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.head == NIL {
+            return None;
+        }
+        let index = self.head;
+        self.head = self.next[index];
+        self.len -= 1;
+        Some(index)
+    }
+
+    // indexはpop()で取り出して、まだ戻していない番号でなければならない
+    pub fn push(&mut self, index: usize) {
+        self.next[index] = self.head;
+        self.head = index;
+        self.len += 1;
+    }
+}
+
+impl<const N: usize> Default for FreeList<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::FreeList;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_free_list() {
+        let mut free: FreeList<3> = FreeList::new();
+        assert_eq!(3, free.len());
+        assert_eq!(Some(0), free.pop());
+        assert_eq!(Some(1), free.pop());
+        assert_eq!(Some(2), free.pop());
+        assert_eq!(None, free.pop());
+        assert!(free.is_empty());
+
+        // 最後に開放したブロックから使う
+        free.push(1);
+        free.push(2);
+        assert_eq!(2, free.len());
+        assert_eq!(Some(2), free.pop());
+        assert_eq!(Some(1), free.pop());
+        assert_eq!(None, free.pop());
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let mut free: FreeList<0> = FreeList::new();
+        assert!(free.is_empty());
+        assert_eq!(None, free.pop());
+    }
+
+    // 疑似乱数の順番で確保と開放を繰り返しても、同じ番号を2度渡さず、開放したものは全て戻る
+    #[test]
+    fn test_against_stack() {
+        const N: usize = 8;
+        let mut free: FreeList<N> = FreeList::new();
+        let mut model: Vec<usize> = (0..N).rev().collect();
+        let mut used: Vec<usize> = Vec::new();
+        let mut seed = 1u32;
+        for _ in 0..10_000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            if seed.is_multiple_of(2) {
+                let index = free.pop();
+                assert_eq!(model.pop(), index);
+                if let Some(index) = index {
+                    assert!(!used.contains(&index));
+                    used.push(index);
+                }
+            } else if !used.is_empty() {
+                let index = used.swap_remove(seed as usize % used.len());
+                free.push(index);
+                model.push(index);
+            }
+            assert_eq!(model.len(), free.len());
+            assert_eq!(N, free.len() + used.len());
+        }
+    }
+}
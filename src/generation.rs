@@ -0,0 +1,90 @@
+// バリアの世代の数え方。Barrierの中身
+// 待ちリストは持たないので、単体でテストできる
+//
+// ❯ rustc --test src/generation.rs
+
+pub struct Generation {
+    arrived: usize,
+    generation: u32, // 全員揃うたびに進める
+}
+
+impl Generation {
+    pub const fn new() -> Self {
+        Generation {
+            arrived: 0,
+            generation: 0,
+        }
+    }
+
+    // n個目に到着したら次の世代に進めてNoneを返す(待っているタスクを起こすのは呼び出し側)
+    // そうでなければ、待つ世代を返す
+    pub fn arrive(&mut self, n: usize) -> Option<u32> {
+        self.arrived += 1;
+        if self.arrived >= n {
+            self.arrived = 0;
+            self.generation = self.generation.wrapping_add(1);
+            return None;
+        }
+        Some(self.generation)
+    }
+
+    pub fn released(&self, generation: u32) -> bool {
+        self.generation != generation
+    }
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::Generation;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_generation() {
+        let mut state = Generation::new();
+        let first = state.arrive(3).unwrap();
+        assert_eq!(Some(first), state.arrive(3));
+        assert!(!state.released(first));
+        // 3個目がリーダーになって、世代が進む
+        assert_eq!(None, state.arrive(3));
+        assert!(state.released(first));
+        assert_eq!(0, state.arrived);
+
+        // もう一度使える。前の世代の待ちとは区別される
+        let second = state.arrive(3).unwrap();
+        assert_ne!(first, second);
+        assert!(!state.released(second));
+    }
+
+    #[test]
+    fn test_wrapping_generation() {
+        let mut state = Generation::new();
+        state.generation = u32::MAX;
+        let generation = state.arrive(2).unwrap();
+        assert_eq!(None, state.arrive(2));
+        assert_eq!(0, state.generation);
+        assert!(state.released(generation));
+        // 1タスクのバリアは待たない
+        assert_eq!(None, state.arrive(1));
+    }
+
+    // n個のタスクが何周しても、各周でリーダーはちょうど1つ、全員が解放される
+    #[test]
+    fn test_rounds() {
+        for n in 1..=5 {
+            let mut state = Generation::new();
+            for _ in 0..10 {
+                let waiting: Vec<u32> = (0..n - 1).map(|_| state.arrive(n).unwrap()).collect();
+                assert!(waiting.iter().all(|g| !state.released(*g)));
+                assert_eq!(None, state.arrive(n));
+                assert!(waiting.iter().all(|g| state.released(*g)));
+            }
+        }
+    }
+}
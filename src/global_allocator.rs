@@ -1,63 +1,180 @@
-// Bump pointer allocator implementation
-// ポインタを増加するだけのアロケータ実装
+// グローバルアロケータ
+// ヒープ本体はTLSF(tlsf::Heap)で、確保も開放もヒープの状態によらず一定時間で終わる。
+// 割り込みハンドラからも確保できるように、ヒープは割り込みを禁止して(IrqMutex)操作する。
+// タスクが確保したメモリはそのタスクの使用量として数え、上限(set_quota)を超える確保は失敗させる。
+// 失敗したときどうするかはOOMフックで決める(デフォルトでは、上限を超えたタスクだけを止める)。
 
-use crate::mutex::Mutex;
+use crate::critical_section::IrqMutex;
+use crate::task::{Task, TaskHandle};
+use crate::tlsf::{alloc_owned, dealloc_owned};
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::UnsafeCell;
-use core::ptr::{self};
+use core::ptr::{self, NonNull};
+use cortex_m::peripheral::scb::VectActive;
+use cortex_m::peripheral::SCB;
+use cortex_m::register::primask;
+use defmt::{error, info};
 
-struct BumpPointerAlloc {
-    head: UnsafeCell<usize>,
-    end: usize,
+pub use crate::tlsf::{Heap, HeapStats, OomCause, TaskHeapStats};
+
+// メモリが足りなかったときにどうするか
+// デフォルトのフックは、上限を超えたタスクだけを止めて(StopTask)、ヒープが尽きたらReturnNullを返す。
+// 上限を超えたタスクを止めずにエラーを返させたいときは、ReturnNullを返すフックを設定して、
+// タスクの中ではVec::try_reserveなどの失敗を返す確保を使う(Box::newなどはpanicする)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    ReturnNull, // nullを返す。Box::newなどはalloc errorでpanicする
+    StopTask,   // 確保しようとしたタスクだけを止める(タスク以外と割り込み禁止中はReturnNull)
+    Reset,      // システムをリセットする
 }
 
-// 使っていないSRAM領域中に、ヒープ領域を即値で定義する。
-// この領域が他に使われないことはプログラマが保証しなければならない。
-const HEAD_ADDR: usize = 0x2001_0000;
-const HEAP_SIZE: usize = 1024;
+// 足りなかった要求と理由、そのときの統計を受け取って、どうするかを返す
+// QuotaExceededのとき、確保しようとしたタスクはTaskHandle::current()
+pub type OomHook = fn(Layout, OomCause, &HeapStats) -> OomAction;
 
-unsafe impl Sync for BumpPointerAlloc {}
+// ヒープ領域はmemory.xの.heapセクション。大きさはリンク時に決まる(RRTOS_HEAP_SIZE)
+// 他のRAMのセクションと重なっていれば、リンカがエラーにする
+extern "C" {
+    static mut __sheap: u8;
+    static mut __eheap: u8;
+}
 
-impl BumpPointerAlloc {
-    const fn new() -> Self {
-        Self {
-            head: UnsafeCell::new(HEAD_ADDR),
-            end: HEAD_ADDR + HEAP_SIZE,
-        }
+// 確保したタスク。割り込みハンドラとカーネルが確保したメモリは、どのタスクにも数えない
+fn owner() -> Option<TaskHandle> {
+    TaskHandle::current().filter(|_| SCB::vect_active() == VectActive::ThreadMode)
+}
+
+// taskの使用量を操作する。確保したタスクはブロックの末尾に書いておく(tlsf::alloc_owned)
+fn with_task_usage(task: NonNull<Task<'static>>, f: &mut dyn FnMut(&mut TaskHeapStats)) {
+    if let Some(task) = TaskHandle::from_ptr(task.as_ptr()) {
+        f(&mut task.heap().lock())
     }
+}
 
+// 割り込みハンドラがタスクの確保の途中に割り込んでも、デッドロックしないようにIrqMutexで守る
+struct GlobalHeap(IrqMutex<Heap>);
+
+unsafe impl GlobalAlloc for GlobalHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let head = self.head.get();
-        let size = layout.size();
-        let align = layout.align();
-        let align_mask = !(align - 1);
-
-        // move start up to the next alignment boundary
-        let start = (*head + align - 1) & align_mask;
-
-        if start + size > self.end {
-            // a null pointer signal an Out Of Memory condition
-            ptr::null_mut()
-        } else {
-            *head = start + size;
-            start as *mut u8
+        let owner = owner().and_then(|task| NonNull::new(task.as_ptr()));
+        let result = {
+            let mut heap = self.0.lock();
+            if !heap.is_initialized() {
+                let start = ptr::addr_of_mut!(__sheap) as usize;
+                let end = ptr::addr_of_mut!(__eheap) as usize;
+                heap.init(start, end - start);
+            }
+            alloc_owned(&mut heap, layout, owner, &with_task_usage)
+        };
+        // フックはヒープのロックを開放してから呼ぶ
+        match result {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(cause) => out_of_memory(layout, cause),
         }
     }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(ptr) = NonNull::new(ptr) else {
+            return;
+        };
+        dealloc_owned(&mut self.0.lock(), ptr, layout, &with_task_usage);
+    }
+}
 
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) {
-        // this allocator never deallocates memory
+// グローバルメモリアロケータの宣言
+#[global_allocator]
+static HEAP: GlobalHeap = GlobalHeap(IrqMutex::new(Heap::new()));
+
+static OOM_HOOK: IrqMutex<OomHook> = IrqMutex::new(default_oom_hook);
+
+fn default_oom_hook(layout: Layout, cause: OomCause, stats: &HeapStats) -> OomAction {
+    match cause {
+        OomCause::HeapExhausted => error!(
+            "out of memory: size={} align={} free={} largest_free={}",
+            layout.size(),
+            layout.align(),
+            stats.free,
+            stats.largest_free
+        ),
+        OomCause::QuotaExceeded => {
+            if let Some(task) = owner() {
+                let usage = task_stats(task);
+                error!(
+                    "heap quota exceeded: task={:x} size={} used={} quota={}",
+                    task.id(),
+                    layout.size(),
+                    usage.used,
+                    usage.quota
+                );
+            }
+            // 上限を超えたタスクだけを止めて、他のタスクは動き続ける
+            return OomAction::StopTask;
+        }
     }
+    OomAction::ReturnNull
 }
 
-unsafe impl GlobalAlloc for Mutex<BumpPointerAlloc> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.lock().alloc(layout)
+pub fn set_oom_hook(hook: OomHook) {
+    *OOM_HOOK.lock() = hook;
+}
+
+fn out_of_memory(layout: Layout, cause: OomCause) -> *mut u8 {
+    let hook = *OOM_HOOK.lock();
+    match hook(layout, cause, &stats()) {
+        OomAction::ReturnNull => ptr::null_mut(),
+        // 割り込みハンドラの中では、割り込まれたタスクを止めない。
+        // クリティカルセクション(IrqMutexなど)の中はPRIMASKがセットされていて、止めるためのsvcが
+        // HardFaultになるので止めない(非特権モードでもPRIMASKは読める)
+        OomAction::StopTask => match owner() {
+            Some(task) if primask::read().is_active() => task.stop(),
+            _ => ptr::null_mut(),
+        },
+        // クリティカルセクションの中は特権モードなので、タスクからでもリセットできる
+        OomAction::Reset => critical_section::with(|_| SCB::sys_reset()),
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock().dealloc(ptr, layout);
+}
+
+// ヒープを使う前ならすべて0
+pub fn stats() -> HeapStats {
+    let heap = HEAP.0.lock();
+    if heap.is_initialized() {
+        heap.stats()
+    } else {
+        HeapStats::default()
     }
 }
 
-// グローバルメモリアロケータの宣言
-#[global_allocator]
-static HEAP: Mutex<BumpPointerAlloc> = Mutex::new(BumpPointerAlloc::new());
+// taskの使用量の上限を設定する。Noneなら上限なし。既に使っている分は開放されない
+pub fn set_quota(task: TaskHandle, quota: Option<usize>) {
+    task.heap().lock().quota = quota;
+}
+
+pub fn task_stats(task: TaskHandle) -> TaskHeapStats {
+    *task.heap().lock()
+}
+
+// defmtで統計を出力する
+pub fn log_stats() {
+    let stats = stats();
+    info!(
+        "heap: size={} used={} free={} peak={} largest_free={} count={} failures={}",
+        stats.size,
+        stats.used,
+        stats.free,
+        stats.peak,
+        stats.largest_free,
+        stats.count,
+        stats.failures
+    );
+}
+
+pub fn log_task_stats(task: TaskHandle) {
+    let stats = task_stats(task);
+    info!(
+        "heap: task={:x} used={} peak={} quota={} count={} failures={}",
+        task.id(),
+        stats.used,
+        stats.peak,
+        stats.quota,
+        stats.count,
+        stats.failures
+    );
+}
@@ -0,0 +1,126 @@
+// ランデブーの受け渡し口。Rendezvousの中身
+// 待ちリストは持たないので、単体でテストできる
+//
+// ❯ rustc --test src/handoff.rs
+
+pub struct Handoff<T> {
+    slot: Option<T>, // 受け渡し中の値。同時に1つだけ
+    put: u32,        // slotに置いた回数。送信側の整理券になる
+    taken: u32,      // slotから受け取った回数
+}
+
+impl<T> Handoff<T> {
+    pub const fn new() -> Self {
+        Handoff {
+            slot: None,
+            put: 0,
+            taken: 0,
+        }
+    }
+
+    // slotが空いていればvalueを置いて、整理券を返す
+    pub fn put(&mut self, value: &mut Option<T>) -> Option<u32> {
+        if self.slot.is_some() {
+            return None;
+        }
+        self.slot = value.take();
+        let ticket = self.put;
+        self.put = self.put.wrapping_add(1);
+        Some(ticket)
+    }
+
+    // ticketの値が受け取られたか
+    pub fn delivered(&self, ticket: u32) -> bool {
+        self.taken != ticket
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        let value = self.slot.take()?;
+        self.taken = self.taken.wrapping_add(1);
+        Some(value)
+    }
+
+    // タイムアウトした送信側の値を返す。まだslotにあれば取り戻す(slotが空く)
+    pub fn reclaim(&mut self, value: &mut Option<T>) -> T {
+        match value.take() {
+            Some(value) => value,
+            None => {
+                self.put = self.put.wrapping_sub(1);
+                self.slot.take().unwrap()
+            }
+        }
+    }
+}
+
+impl<T> Default for Handoff<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Handoff;
+
+    #[test]
+    fn test_handoff() {
+        let mut state: Handoff<u32> = Handoff::new();
+        assert_eq!(None, state.take());
+
+        let mut first = Some(1);
+        let ticket = state.put(&mut first).unwrap();
+        assert_eq!(None, first);
+        // 受け渡し中は、他の送信側は置けない
+        let mut second = Some(2);
+        assert_eq!(None, state.put(&mut second));
+        assert_eq!(Some(2), second);
+        assert!(!state.delivered(ticket));
+
+        assert_eq!(Some(1), state.take());
+        assert!(state.delivered(ticket));
+
+        let ticket = state.put(&mut second).unwrap();
+        assert!(!state.delivered(ticket));
+        assert_eq!(Some(2), state.take());
+        assert!(state.delivered(ticket));
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let mut state: Handoff<u32> = Handoff::new();
+        // slotに置いたまま受け取られなかった
+        let mut value = Some(1);
+        state.put(&mut value).unwrap();
+        assert_eq!(1, state.reclaim(&mut value));
+        assert_eq!(0, state.put);
+        assert_eq!(None, state.take());
+
+        // slotに置けないままタイムアウトした
+        let mut other = Some(3);
+        state.put(&mut Some(2)).unwrap();
+        assert_eq!(3, state.reclaim(&mut other));
+        let ticket = state.put;
+        assert_eq!(Some(2), state.take());
+        assert!(state.delivered(ticket.wrapping_sub(1)));
+
+        // 次の送信側の整理券は、受け取った数と合う
+        let mut next = Some(4);
+        let ticket = state.put(&mut next).unwrap();
+        assert!(!state.delivered(ticket));
+    }
+
+    // 取り戻した後の整理券が、後の送信側の受け渡しを取り違えない
+    #[test]
+    fn test_reclaim_then_handoff() {
+        let mut state: Handoff<u32> = Handoff::new();
+        let mut a = Some(1);
+        let ta = state.put(&mut a).unwrap();
+        assert_eq!(1, state.reclaim(&mut a));
+        let mut b = Some(2);
+        let tb = state.put(&mut b).unwrap();
+        assert_eq!(ta, tb);
+        assert!(!state.delivered(tb));
+        assert_eq!(Some(2), state.take());
+        assert!(state.delivered(tb));
+    }
+}
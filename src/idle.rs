@@ -0,0 +1,144 @@
+// アイドルタスク
+// 全てのタスクが待っているときに、スケジューラが実行するカーネルのタスク。アプリケーションは登録しなくてよい。
+// 登録したフック(add_hook)を順に呼んでから、カーネルに戻って次の期限まで眠る。
+// どう眠るか(wfi/wfe/tickless/deep sleep)は、次の期限までのtick数とPolicyで決める。
+// フックはアイドルタスクの中で呼ばれるので、待つ処理をしてはいけない。
+//
+// fn feed_watchdog() { ... }
+// idle::add_hook(feed_watchdog)?;
+// idle::set_policy(Policy { deep_sleep_min: Some(100), ..Policy::new() });
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use crate::critical_section::IrqMutex;
+use crate::syscall;
+use crate::task::{AlignedStack, Task};
+
+pub const MAX_IDLE_HOOKS: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    TooManyHooks,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    Wfi,       // tickを止めずに、次の割り込みまで眠る
+    Wfe,       // wfiと同じだが、もう一方のコアのsevでも起きる
+    Tickless,  // tickを止めて、次の期限まで眠る
+    DeepSleep, // Ticklessに加えて、StayAwakeが許す深さ(power::PowerState)まで眠る
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub tickless_min: u32, // 次の期限までこのtick数以上あれば、tickを止める
+    pub deep_sleep_min: Option<u32>, // このtick数以上あればdeep sleep。Noneならしない
+    pub wfe: bool,         // tickを止めないときに、wfiの代わりにwfeを使う
+}
+
+impl Policy {
+    pub const fn new() -> Self {
+        Policy {
+            tickless_min: 2,
+            deep_sleep_min: None,
+            wfe: false,
+        }
+    }
+
+    // ticksは次の期限までのtick数(期限がなければu32::MAX)
+    // 次のtickより先まで眠れなければ、tickを止めない
+    pub fn choose(&self, ticks: u32) -> SleepMode {
+        if ticks < self.tickless_min.max(2) {
+            if self.wfe {
+                SleepMode::Wfe
+            } else {
+                SleepMode::Wfi
+            }
+        } else if self.deep_sleep_min.is_some_and(|min| ticks >= min) {
+            SleepMode::DeepSleep
+        } else {
+            SleepMode::Tickless
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static POLICY: IrqMutex<Policy> = IrqMutex::new(Policy::new());
+type Hooks = [Option<fn()>; MAX_IDLE_HOOKS];
+static HOOKS: IrqMutex<Hooks> = IrqMutex::new([None; MAX_IDLE_HOOKS]);
+
+pub fn set_policy(policy: Policy) {
+    *POLICY.lock() = policy;
+}
+
+pub fn policy() -> Policy {
+    *POLICY.lock()
+}
+
+pub fn add_hook(hook: fn()) -> Result<(), Error> {
+    let mut hooks = HOOKS.lock();
+    let slot = hooks
+        .iter_mut()
+        .find(|h| h.is_none())
+        .ok_or(Error::TooManyHooks)?;
+    *slot = Some(hook);
+    Ok(())
+}
+
+// アイドルタスクを作る。スケジューラが1度だけ呼ぶ
+pub(crate) fn task() -> Task<'static> {
+    #[link_section = ".uninit.STACKS"]
+    static mut IDLE_STACK: AlignedStack = AlignedStack(MaybeUninit::uninit());
+    Task::new(unsafe { &mut *addr_of_mut!(IDLE_STACK) }, idle_main)
+}
+
+// フックを呼んだら、カーネルに戻る。眠るのはカーネル(systick::idle)
+fn idle_main() -> ! {
+    loop {
+        // ロックを持ったままフックを呼ばない
+        let hooks = *HOOKS.lock();
+        for hook in hooks.iter().flatten() {
+            hook();
+        }
+        syscall::back_to_kernel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, SleepMode};
+
+    #[test]
+    fn test_choose() {
+        let policy = Policy::new();
+        assert_eq!(SleepMode::Wfi, policy.choose(1));
+        assert_eq!(SleepMode::Tickless, policy.choose(2));
+        assert_eq!(SleepMode::Tickless, policy.choose(u32::MAX));
+
+        let policy = Policy {
+            tickless_min: 10,
+            deep_sleep_min: Some(100),
+            wfe: true,
+        };
+        assert_eq!(SleepMode::Wfe, policy.choose(9));
+        assert_eq!(SleepMode::Tickless, policy.choose(10));
+        assert_eq!(SleepMode::Tickless, policy.choose(99));
+        assert_eq!(SleepMode::DeepSleep, policy.choose(100));
+        assert_eq!(SleepMode::DeepSleep, policy.choose(u32::MAX));
+
+        // 次のtickまでしか眠れないときは、deep sleepしない
+        let policy = Policy {
+            tickless_min: 0,
+            deep_sleep_min: Some(0),
+            wfe: false,
+        };
+        assert_eq!(SleepMode::Wfi, policy.choose(1));
+        assert_eq!(SleepMode::DeepSleep, policy.choose(2));
+    }
+}
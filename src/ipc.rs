@@ -0,0 +1,669 @@
+// 同期メッセージパッシング(L4スタイルのIPC)
+// タスク同士がstaticを共有せずに、システムコールでメッセージを直接受け渡す。
+// 相手の準備ができるまで呼び出したタスクは待つ。カーネルはメッセージをバッファリングしない。
+// メッセージはレジスタ5個分(Message)と、任意でバイト列。バイト列は受信側のバッファの長さで切り詰める。
+// call()は送信して、相手がreply()するまで待つ。ドライバをサーバとして普通のタスクで動かせる。
+// 宛先(送信元)は呼び出したタスクのケーパビリティテーブルの番号で指定し、
+// send/callにはRights::SEND、送信元を指定したreceiveにはRights::RECEIVEが必要。
+// reply()の権限は、call()を受信したときにその相手へ1回だけ与えられる(Endpoint::callers)。
+// どれもタスクからだけ呼べる。
+// カーネルはタスクの代わりにバッファを読み書きするので、引数のアドレスを確認する。
+// Requestは呼び出したタスクのスタック、バッファはRAM(送信するバッファはフラッシュも)になければBadAddress。
+//
+// // サーバ(LEDドライバ)
+// loop {
+//     let req = ipc::receive(None, &mut [], None)?;
+//     led::toggle();
+//     ipc::reply(req.sender, &Message::new(0), &[])?;
+// }
+// // クライアント
+// let (reply, _) = ipc::call(led_server, &Message::new(LED_TOGGLE), &[], &mut [], None)?;
+
+use core::mem;
+use core::ptr::{self, NonNull};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+
+use crate::capability::{CapId, Object, Rights};
+use crate::syscall;
+use crate::systick;
+use crate::task::TaskHandle;
+use crate::user_memory;
+use crate::wait_list::WaitList;
+
+pub const MESSAGE_WORDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Message {
+    pub label: u32, // 要求の種類など。意味はタスク同士で決める
+    pub words: [u32; MESSAGE_WORDS],
+}
+
+impl Message {
+    pub const fn new(label: u32) -> Self {
+        Message {
+            label,
+            words: [0; MESSAGE_WORDS],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    NotWaiting,     // reply()の相手がcall()で返信を待っていない
+    NoCapability,   // ケーパビリティがないか、権限が足りない
+    BadAddress,     // バッファがタスクの読み書きできる場所にない
+    TooManyCallers, // 返信していないcall()が多すぎて、call()を受信できない
+}
+
+#[derive(Clone, Copy)]
+pub struct Received {
+    pub sender: TaskHandle,
+    pub message: Message,
+    pub len: usize, // bufに受信したバイト数
+}
+
+// システムコールの戻り値(r0)
+const IPC_OK: u32 = 0;
+const IPC_TIMEOUT: u32 = 1;
+const IPC_NOT_WAITING: u32 = 2;
+const IPC_RETRY: u32 = 3; // 宛先の送信待ちリストがいっぱい。タスクに戻ってやり直す
+const IPC_NO_CAPABILITY: u32 = 4;
+const IPC_BAD_ADDRESS: u32 = 5;
+const IPC_TOO_MANY_CALLERS: u32 = 6;
+
+// システムコールの引数。呼び出したタスクのスタックに置いて、アドレスをr1で渡す。
+// 完了するまでタスクは待っているので、カーネルは相手のRequestを直接読み書きできる。
+pub(crate) struct Request {
+    cap: Option<CapId>,       // 宛先。receiveでは送信元(Noneなら誰からでも)
+    peer: Option<TaskHandle>, // capが指すタスク。カーネルがセットする。replyでは宛先
+    message: Message,
+    send_buf: *const u8,
+    send_len: usize,
+    recv_buf: *mut u8,
+    recv_len: usize, // 受信バッファの長さ。完了すると受信したバイト数
+    sender: Option<TaskHandle>,
+    call: bool,
+    deadline: Option<u32>,
+}
+
+impl Request {
+    fn new(cap: Option<CapId>, message: Message, timeout: Option<u32>) -> Self {
+        Request {
+            cap,
+            peer: None,
+            message,
+            send_buf: NonNull::dangling().as_ptr(),
+            send_len: 0,
+            recv_buf: NonNull::dangling().as_ptr(),
+            recv_len: 0,
+            sender: None,
+            call: false,
+            deadline: timeout.map(systick::deadline),
+        }
+    }
+}
+
+enum State {
+    Idle,
+    Sending(*mut Request),
+    Receiving(*mut Request),
+    WaitingReply(*mut Request), // call()で送信し終えて、返信を待っている
+}
+
+// タスクごとのIPCの状態
+pub(crate) struct Endpoint {
+    state: State,
+    senders: WaitList, // このタスクへ送信しようとして待っているタスク
+    callers: WaitList, // このタスクがcall()を受信して、まだ返信していないタスク
+}
+
+impl Endpoint {
+    pub(crate) const fn new() -> Self {
+        Endpoint {
+            state: State::Idle,
+            senders: WaitList::new(),
+            callers: WaitList::new(),
+        }
+    }
+}
+
+// 相手がreceive()するまで待って送信する。timeoutはtick数、Noneなら無期限に待つ
+pub fn send(to: CapId, message: &Message, buf: &[u8], timeout: Option<u32>) -> Result<(), Error> {
+    let mut request = Request::new(Some(to), *message, timeout);
+    request.send_buf = buf.as_ptr();
+    request.send_len = buf.len();
+    call_kernel(syscall::SYSCALL_IPC_SEND, &mut request)
+}
+
+// fromから(Noneなら誰からでも)送信されるまで待って受信する
+pub fn receive(
+    from: Option<CapId>,
+    buf: &mut [u8],
+    timeout: Option<u32>,
+) -> Result<Received, Error> {
+    let mut request = Request::new(from, Message::default(), timeout);
+    request.recv_buf = buf.as_mut_ptr();
+    request.recv_len = buf.len();
+    call_kernel(syscall::SYSCALL_IPC_RECEIVE, &mut request)?;
+    Ok(Received {
+        sender: request.sender.unwrap(),
+        message: request.message,
+        len: request.recv_len,
+    })
+}
+
+// 送信して、相手がreply()するまで待つ。返信のメッセージとreply_bufに受信したバイト数を返す
+// timeoutは送信と返信を合わせた時間
+pub fn call(
+    to: CapId,
+    message: &Message,
+    buf: &[u8],
+    reply_buf: &mut [u8],
+    timeout: Option<u32>,
+) -> Result<(Message, usize), Error> {
+    let mut request = Request::new(Some(to), *message, timeout);
+    request.send_buf = buf.as_ptr();
+    request.send_len = buf.len();
+    request.recv_buf = reply_buf.as_mut_ptr();
+    request.recv_len = reply_buf.len();
+    request.call = true;
+    call_kernel(syscall::SYSCALL_IPC_CALL, &mut request)?;
+    Ok((request.message, request.recv_len))
+}
+
+// call()で待っているタスクに返信する。待たない
+// toはreceive()で受け取ったReceived::sender
+pub fn reply(to: TaskHandle, message: &Message, buf: &[u8]) -> Result<(), Error> {
+    let mut request = Request::new(None, *message, None);
+    request.peer = Some(to);
+    request.send_buf = buf.as_ptr();
+    request.send_len = buf.len();
+    call_kernel(syscall::SYSCALL_IPC_REPLY, &mut request)
+}
+
+fn call_kernel(number: u32, request: &mut Request) -> Result<(), Error> {
+    assert!(TaskHandle::current().is_some(), "ipc outside a task");
+    loop {
+        match syscall::ipc(number, request) {
+            IPC_OK => return Ok(()),
+            IPC_TIMEOUT => return Err(Error::Timeout),
+            IPC_NOT_WAITING => return Err(Error::NotWaiting),
+            IPC_NO_CAPABILITY => return Err(Error::NoCapability),
+            IPC_BAD_ADDRESS => return Err(Error::BadAddress),
+            IPC_TOO_MANY_CALLERS => return Err(Error::TooManyCallers),
+            _ => syscall::back_to_kernel(),
+        }
+    }
+}
+
+// ここから下はSVCall handlerとカーネルから呼ばれる。
+// 呼び出したタスクのフレームはframe、待っている相手のフレームは相手のスタックにある。
+
+// 待っているタスクのEndpoint。タスクは実行中でないので、ハンドラとカーネルだけが触る
+unsafe fn endpoint(task: TaskHandle) -> &'static mut Endpoint {
+    &mut *task.ipc()
+}
+
+pub(crate) fn handle(frame: &mut ExceptionFrame) {
+    let Some(me) = TaskHandle::current() else {
+        return;
+    };
+    let result = match request_at(me, frame.r1() as usize) {
+        Some(request) => dispatch(me, frame.r0(), request),
+        None => IPC_BAD_ADDRESS,
+    };
+    unsafe { frame.set_r0(result) };
+    // 待つことになったら、タスクを切り替える
+    if is_waiting(me) {
+        SCB::set_pendsv();
+    }
+}
+
+// r1で渡されたRequestとバッファを確かめる。タスクが自分で読み書きできない場所を指していればNone
+fn request_at(me: TaskHandle, addr: usize) -> Option<&'static mut Request> {
+    let (size, align) = (mem::size_of::<Request>(), mem::align_of::<Request>());
+    if !user_memory::argument_ok(&me.stack(), addr, size, align) {
+        return None;
+    }
+    let request = unsafe { &mut *(addr as *mut Request) };
+    let (send, send_len) = (request.send_buf as usize, request.send_len);
+    let (recv, recv_len) = (request.recv_buf as usize, request.recv_len);
+    user_memory::buffers_ok(send, send_len, recv, recv_len).then_some(request)
+}
+
+fn dispatch(me: TaskHandle, number: u32, request: &mut Request) -> u32 {
+    match number {
+        syscall::SYSCALL_IPC_SEND | syscall::SYSCALL_IPC_CALL => {
+            if resolve(me, request, Rights::SEND) {
+                handle_send(me, request)
+            } else {
+                IPC_NO_CAPABILITY
+            }
+        }
+        syscall::SYSCALL_IPC_RECEIVE => {
+            if resolve(me, request, Rights::RECEIVE) {
+                handle_receive(me, request)
+            } else {
+                IPC_NO_CAPABILITY
+            }
+        }
+        _ => handle_reply(me, request),
+    }
+}
+
+// capが指すタスクをpeerにセットする。capがタスクを指していないか、rightsがなければfalse
+// タスクがpeerに書いた値は使わない
+fn resolve(me: TaskHandle, request: &mut Request, rights: Rights) -> bool {
+    request.peer = None;
+    let Some(cap) = request.cap else {
+        return true;
+    };
+    match me.capabilities().check(cap, rights) {
+        Ok(Object::Task(task)) => {
+            request.peer = Some(task);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn handle_send(me: TaskHandle, request: &mut Request) -> u32 {
+    let Some(to) = request.peer else {
+        return IPC_NOT_WAITING;
+    };
+    let dest = unsafe { endpoint(to) };
+    if let State::Receiving(r) = dest.state {
+        let r = unsafe { &mut *r };
+        // 返信していないcall()が多すぎれば、受信側がreceive()したときにエラーにする
+        if r.peer.is_none_or(|from| from == me) && (!request.call || dest.callers.push(me)) {
+            transfer(request, me, r);
+            complete(to);
+            if !request.call {
+                return IPC_OK;
+            }
+            if request.deadline.is_some_and(systick::is_expired) {
+                return IPC_TIMEOUT;
+            }
+            return block(me, State::WaitingReply(request), request.deadline);
+        }
+    }
+    if request.deadline.is_some_and(systick::is_expired) {
+        return IPC_TIMEOUT;
+    }
+    if !dest.senders.push(me) {
+        return IPC_RETRY;
+    }
+    block(me, State::Sending(request), request.deadline)
+}
+
+fn handle_receive(me: TaskHandle, request: &mut Request) -> u32 {
+    let ep = unsafe { endpoint(me) };
+    let sender = match request.peer {
+        Some(from) => ep.senders.contains(from).then_some(from),
+        None => ep.senders.first(),
+    };
+    if let Some(sender) = sender {
+        let sender_ep = unsafe { endpoint(sender) };
+        if let State::Sending(s) = sender_ep.state {
+            let s = unsafe { &mut *s };
+            if s.call && !ep.callers.push(sender) {
+                return IPC_TOO_MANY_CALLERS;
+            }
+            ep.senders.remove(sender);
+            transfer(s, sender, request);
+            if s.call {
+                sender_ep.state = State::WaitingReply(s);
+            } else {
+                complete(sender);
+            }
+            return IPC_OK;
+        }
+        ep.senders.remove(sender);
+    }
+    if request.deadline.is_some_and(systick::is_expired) {
+        return IPC_TIMEOUT;
+    }
+    block(me, State::Receiving(request), request.deadline)
+}
+
+fn handle_reply(me: TaskHandle, request: &mut Request) -> u32 {
+    // peerはタスクが渡した値なので、call()を受信した相手か確かめてから使う
+    let ep = unsafe { endpoint(me) };
+    let Some(to) = request.peer.filter(|&to| ep.callers.contains(to)) else {
+        return IPC_NOT_WAITING;
+    };
+    ep.callers.remove(to);
+    match unsafe { endpoint(to) }.state {
+        State::WaitingReply(r) => {
+            transfer(request, me, unsafe { &mut *r });
+            complete(to);
+            IPC_OK
+        }
+        _ => IPC_NOT_WAITING,
+    }
+}
+
+fn transfer(from: &Request, sender: TaskHandle, to: &mut Request) {
+    to.message = from.message;
+    let len = from.send_len.min(to.recv_len);
+    unsafe { ptr::copy_nonoverlapping(from.send_buf, to.recv_buf, len) };
+    to.recv_len = len;
+    to.sender = Some(sender);
+}
+
+// 呼び出したタスクを待たせる。相手が完了させるとIPC_OKに書き換わる
+// タスクの切り替えはhandle()がする
+fn block(me: TaskHandle, state: State, deadline: Option<u32>) -> u32 {
+    unsafe { endpoint(me) }.state = state;
+    me.block(deadline);
+    IPC_TIMEOUT
+}
+
+// 送信、受信、返信のどれかを待っている
+pub(crate) fn is_waiting(task: TaskHandle) -> bool {
+    !matches!(unsafe { endpoint(task) }.state, State::Idle)
+}
+
+// 待っている相手を完了させて起こす
+fn complete(task: TaskHandle) {
+    unsafe { endpoint(task) }.state = State::Idle;
+    unsafe { (*task.frame()).set_r0(IPC_OK) };
+    task.wake();
+}
+
+// タイムアウトしたタスクのIPCを取り消す。カーネルがタスクを実行可能に戻すときに呼ぶ
+pub(crate) fn cancel(task: TaskHandle) {
+    let ep = unsafe { endpoint(task) };
+    match ep.state {
+        State::Sending(r) => {
+            if let Some(to) = unsafe { (*r).peer } {
+                unsafe { endpoint(to) }.senders.remove(task);
+            }
+        }
+        State::WaitingReply(r) => {
+            if let Some(to) = unsafe { (*r).peer } {
+                unsafe { endpoint(to) }.callers.remove(task);
+            }
+        }
+        _ => {}
+    }
+    ep.state = State::Idle;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::{AlignedStack, Task};
+    use core::mem::MaybeUninit;
+
+    // テストではタスクを実行しない
+    fn app() -> ! {
+        unreachable!()
+    }
+
+    fn stack() -> AlignedStack {
+        AlignedStack(MaybeUninit::uninit())
+    }
+
+    // 待っていたタスクのsvcの戻り値
+    fn result(task: TaskHandle) -> u32 {
+        unsafe { (*task.frame()).r0() }
+    }
+
+    fn idle(task: TaskHandle) -> bool {
+        matches!(unsafe { endpoint(task) }.state, State::Idle)
+    }
+
+    #[test]
+    fn test_send_then_receive() {
+        let (mut s0, mut s1) = (stack(), stack());
+        let server = Task::new(&mut s0, app);
+        let server = TaskHandle::from_task(&server);
+        let mut client = Task::new(&mut s1, app);
+        let cap = client.grant(Object::Task(server), Rights::SEND).unwrap();
+        let client = TaskHandle::from_task(&client);
+
+        let data = [1, 2, 3];
+        let mut send = Request::new(Some(cap), Message::new(7), None);
+        send.send_buf = data.as_ptr();
+        send.send_len = data.len();
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(client, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert!(unsafe { endpoint(server) }.senders.contains(client));
+
+        // 受信バッファが短ければ切り詰める
+        let mut buf = [0; 2];
+        let mut recv = Request::new(None, Message::default(), None);
+        recv.recv_buf = buf.as_mut_ptr();
+        recv.recv_len = buf.len();
+        assert_eq!(
+            IPC_OK,
+            dispatch(server, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+        assert_eq!(7, recv.message.label);
+        assert_eq!(2, recv.recv_len);
+        assert!(recv.sender == Some(client));
+        assert_eq!([1, 2], buf);
+        assert_eq!(IPC_OK, result(client));
+        assert!(idle(client));
+        assert!(unsafe { endpoint(server) }.senders.is_empty());
+    }
+
+    #[test]
+    fn test_receive_then_send() {
+        let (mut s0, mut s1, mut s2) = (stack(), stack(), stack());
+        let mut server = Task::new(&mut s0, app);
+        let mut client = Task::new(&mut s1, app);
+        let mut other = Task::new(&mut s2, app);
+        let (client_h, other_h) = (
+            TaskHandle::from_task(&client),
+            TaskHandle::from_task(&other),
+        );
+        let from = server
+            .grant(Object::Task(client_h), Rights::RECEIVE)
+            .unwrap();
+        let server = TaskHandle::from_task(&server);
+        let cap = client.grant(Object::Task(server), Rights::SEND).unwrap();
+        let other_cap = other.grant(Object::Task(server), Rights::SEND).unwrap();
+
+        // clientからだけ受信する
+        let mut recv = Request::new(Some(from), Message::default(), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(server, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+        assert!(!idle(server));
+
+        let mut send = Request::new(Some(other_cap), Message::new(4), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(other_h, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert!(!idle(server));
+
+        let mut send = Request::new(Some(cap), Message::new(3), None);
+        assert_eq!(
+            IPC_OK,
+            dispatch(client_h, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert_eq!(IPC_OK, result(server));
+        assert!(idle(server));
+        assert_eq!(3, recv.message.label);
+        assert!(recv.sender == Some(client_h));
+    }
+
+    #[test]
+    fn test_call_and_reply() {
+        let (mut s0, mut s1, mut s2) = (stack(), stack(), stack());
+        let server = Task::new(&mut s0, app);
+        let server = TaskHandle::from_task(&server);
+        let mut client = Task::new(&mut s1, app);
+        let cap = client.grant(Object::Task(server), Rights::SEND).unwrap();
+        let client = TaskHandle::from_task(&client);
+        let other = Task::new(&mut s2, app);
+        let other = TaskHandle::from_task(&other);
+
+        let mut recv = Request::new(None, Message::default(), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(server, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+
+        let mut buf = [0; 4];
+        let mut call = Request::new(Some(cap), Message::new(1), None);
+        call.recv_buf = buf.as_mut_ptr();
+        call.recv_len = buf.len();
+        call.call = true;
+        // 送信は終わって、返信を待つ
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(client, syscall::SYSCALL_IPC_CALL, &mut call)
+        );
+        assert_eq!(IPC_OK, result(server));
+        assert!(!idle(client));
+        assert!(unsafe { endpoint(server) }.callers.contains(client));
+
+        // call()していないタスクには返信できない
+        let mut reply = Request::new(None, Message::new(2), None);
+        reply.peer = Some(other);
+        assert_eq!(
+            IPC_NOT_WAITING,
+            dispatch(server, syscall::SYSCALL_IPC_REPLY, &mut reply)
+        );
+
+        let data = [9, 8];
+        let mut reply = Request::new(None, Message::new(2), None);
+        reply.peer = Some(client);
+        reply.send_buf = data.as_ptr();
+        reply.send_len = data.len();
+        assert_eq!(
+            IPC_OK,
+            dispatch(server, syscall::SYSCALL_IPC_REPLY, &mut reply)
+        );
+        assert_eq!(IPC_OK, result(client));
+        assert!(idle(client));
+        assert_eq!(2, call.message.label);
+        assert_eq!(2, call.recv_len);
+        assert_eq!([9, 8, 0, 0], buf);
+
+        // 返信は一度だけ
+        assert_eq!(
+            IPC_NOT_WAITING,
+            dispatch(server, syscall::SYSCALL_IPC_REPLY, &mut reply)
+        );
+    }
+
+    #[test]
+    fn test_cancel() {
+        let (mut s0, mut s1, mut s2) = (stack(), stack(), stack());
+        let server = Task::new(&mut s0, app);
+        let server = TaskHandle::from_task(&server);
+        let mut sender = Task::new(&mut s1, app);
+        let cap = sender.grant(Object::Task(server), Rights::SEND).unwrap();
+        let sender = TaskHandle::from_task(&sender);
+        let mut caller = Task::new(&mut s2, app);
+        let caller_cap = caller.grant(Object::Task(server), Rights::SEND).unwrap();
+        let caller = TaskHandle::from_task(&caller);
+
+        // 送信待ちを取り消すと、送信待ちリストから外れる
+        let mut send = Request::new(Some(cap), Message::new(1), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(sender, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        cancel(sender);
+        assert!(idle(sender));
+        assert!(unsafe { endpoint(server) }.senders.is_empty());
+
+        // 返信待ちを取り消すと、返信できなくなる
+        let mut recv = Request::new(None, Message::default(), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(server, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+        let mut call = Request::new(Some(caller_cap), Message::new(1), None);
+        call.call = true;
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(caller, syscall::SYSCALL_IPC_CALL, &mut call)
+        );
+        cancel(caller);
+        assert!(idle(caller));
+        let mut reply = Request::new(None, Message::new(2), None);
+        reply.peer = Some(caller);
+        assert_eq!(
+            IPC_NOT_WAITING,
+            dispatch(server, syscall::SYSCALL_IPC_REPLY, &mut reply)
+        );
+
+        // 受信待ちを取り消せば、送信側が待つ
+        let mut recv = Request::new(None, Message::default(), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(server, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+        cancel(server);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(sender, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert!(unsafe { endpoint(server) }.senders.contains(sender));
+    }
+
+    #[test]
+    fn test_notify_during_receive() {
+        let (mut s0, mut s1) = (stack(), stack());
+        let mut server = Task::new(&mut s0, app);
+        let server_h = TaskHandle::from_task(&server);
+        let mut client = Task::new(&mut s1, app);
+        let cap = client.grant(Object::Task(server_h), Rights::SEND).unwrap();
+        let client = TaskHandle::from_task(&client);
+
+        let mut recv = Request::new(None, Message::default(), None);
+        assert_eq!(
+            IPC_TIMEOUT,
+            dispatch(server_h, syscall::SYSCALL_IPC_RECEIVE, &mut recv)
+        );
+        // 通知などで起こされても(notify()はwake()する)、受信を待ち続ける
+        server_h.wake();
+        assert!(!server.exec());
+        assert!(!idle(server_h));
+
+        let mut send = Request::new(Some(cap), Message::new(5), None);
+        assert_eq!(
+            IPC_OK,
+            dispatch(client, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert!(server.exec());
+        assert_eq!(IPC_OK, result(server_h));
+        assert_eq!(5, recv.message.label);
+    }
+
+    #[test]
+    fn test_no_capability() {
+        let (mut s0, mut s1) = (stack(), stack());
+        let server = Task::new(&mut s0, app);
+        let server = TaskHandle::from_task(&server);
+        let mut client = Task::new(&mut s1, app);
+        let cap = client.grant(Object::Task(server), Rights::RECEIVE).unwrap();
+        let client = TaskHandle::from_task(&client);
+
+        let mut send = Request::new(Some(cap), Message::new(1), None);
+        assert_eq!(
+            IPC_NO_CAPABILITY,
+            dispatch(client, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        // capを指定しなければ、タスクが書いたpeerには送らない
+        let mut send = Request::new(None, Message::new(1), None);
+        send.peer = Some(server);
+        assert_eq!(
+            IPC_NOT_WAITING,
+            dispatch(client, syscall::SYSCALL_IPC_SEND, &mut send)
+        );
+        assert!(unsafe { endpoint(server) }.senders.is_empty());
+    }
+}
@@ -1,11 +1,49 @@
 #![no_std]
+pub mod barrier;
+pub mod capability;
+pub mod clock;
+pub mod condvar;
+pub mod critical_section;
+pub mod deadlock;
+pub mod event_flags;
+pub mod event_group;
 pub mod exceptions;
+pub mod fifo;
+pub mod free_list;
+pub mod generation;
+#[cfg(feature = "alloc")]
 pub mod global_allocator;
+pub mod handoff;
+pub mod idle;
+pub mod ipc;
 pub mod led;
 pub mod linked_list;
+pub mod lock_order;
+pub mod message_buffer;
 pub mod mutex;
+pub mod notification;
+pub mod notify;
+pub mod owner_count;
+pub mod pool;
+pub mod power;
+pub mod queue;
+pub mod recursive_mutex;
+pub mod rendezvous;
+pub mod ring_buffer;
 pub mod rwlock;
 pub mod scheduler;
+pub mod spinlock;
+pub mod spsc;
+pub mod stream_buffer;
 pub mod syscall;
 pub mod systick;
 pub mod task;
+pub mod tick;
+pub mod timer;
+pub mod timer_wheel;
+#[cfg(feature = "alloc")]
+pub mod tlsf;
+pub mod user_memory;
+pub mod wait_list;
+pub mod waiters;
+pub mod wake;
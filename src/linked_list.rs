@@ -1,4 +1,5 @@
 #![cfg_attr(test, no_std)]
+#[cfg(test)]
 extern crate alloc;
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut};
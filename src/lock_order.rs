@@ -0,0 +1,88 @@
+// タスク(またはコア)が保持しているロックのレベルの集合(spinlock::LockLevel)
+// ビットnがレベルn。レベル0のロックは順序を決めないので記録しない。
+// 取った順の逆に開放しなくても、残っているロックから一番高いレベルが分かる。
+// ハードウェアには触らないので、単体でテストできる
+//
+// ❯ rustc --test src/lock_order.rs
+
+// レベルは1からMAX_LEVELまで
+pub const MAX_LEVEL: u8 = 31;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeldLevels(u32);
+
+impl HeldLevels {
+    pub const fn new() -> Self {
+        HeldLevels(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        HeldLevels(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    // 保持しているロックの一番高いレベル。何も保持していなければ0
+    pub fn highest(self) -> u8 {
+        (u32::BITS - self.0.leading_zeros()).saturating_sub(1) as u8
+    }
+
+    pub fn contains(self, level: u8) -> bool {
+        level != 0 && self.0 & (1 << level) != 0
+    }
+
+    // 保持したままlevelのロックを取ってよいか。同じレベルもネストできない
+    pub fn allows(self, level: u8) -> bool {
+        level == 0 || self.highest() < level
+    }
+
+    pub fn with(self, level: u8) -> Self {
+        debug_assert!(level <= MAX_LEVEL, "lock level {} is too high", level);
+        if level == 0 {
+            return self;
+        }
+        HeldLevels(self.0 | 1 << level)
+    }
+
+    pub fn without(self, level: u8) -> Self {
+        if level == 0 {
+            return self;
+        }
+        HeldLevels(self.0 & !(1 << level))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HeldLevels, MAX_LEVEL};
+
+    #[test]
+    fn test_allows() {
+        let none = HeldLevels::new();
+        assert!(none.allows(1));
+        let one = none.with(1);
+        assert!(one.allows(2));
+        let two = none.with(2);
+        assert!(!two.allows(1));
+        assert!(!two.allows(2)); // 同じレベルもネストできない
+        assert!(two.allows(0)); // レベル0は確認しない
+        assert_eq!(none, none.with(0));
+        assert_eq!(MAX_LEVEL, none.with(MAX_LEVEL).highest());
+    }
+
+    // A(1), B(2)の順に取って、Aから開放する
+    #[test]
+    fn test_out_of_order_release() {
+        let a = HeldLevels::new().with(1);
+        let ab = a.with(2);
+        assert_eq!(2, ab.highest());
+        let b = ab.without(1);
+        assert_eq!(2, b.highest());
+        assert!(!b.allows(1));
+        let none = b.without(2);
+        assert_eq!(0, none.highest());
+        assert!(none.allows(1));
+    }
+}
@@ -4,10 +4,7 @@
 #![no_std]
 #![no_main]
 
-extern crate alloc;
-use alloc::boxed::Box;
-use core::{mem::MaybeUninit, ptr::addr_of_mut};
-use cortex_m::asm::{self, wfi};
+use cortex_m::asm;
 use cortex_m_rt::entry;
 use defmt::*;
 use defmt_rtt as _;
@@ -20,12 +17,11 @@ use rp2040_hal::{
     watchdog::Watchdog,
 };
 use rrtos::{
-    led,
-    linked_list::ListItem,
+    clock, led,
     rwlock::RwLock,
     scheduler::Scheduler,
-    syscall, systick,
-    task::{AlignedStack, Task},
+    static_task, syscall, systick,
+    timer::{self, Mode, Timer},
 };
 
 #[link_section = ".boot2"]
@@ -55,34 +51,20 @@ fn app_main2() -> ! {
     }
 }
 
-fn app_main3() -> ! {
-    info!("app_main3()");
-    let mut i = 0;
-    loop {
-        info!("app_main3(): {}", i);
-        i += 3;
-        led::toggle();
-        SCHEDULER
-            .write()
-            .current_task()
-            .unwrap()
-            .wait_until(systick::count_get().wrapping_add(5));
-    }
+// 5tickごとにLEDを反転する。タイマーサービスタスクの中で呼ばれる
+fn blink(_timer: &'static Timer) {
+    led::toggle();
 }
 
-fn app_idle() -> ! {
-    info!("app_idle");
-    loop {
-        info!("app_idle() wfi");
-        wfi();
-        // SysTickによってsleepから目覚め、set PendSVによってタスクスイッチが発生する。
-        // 次のタスクが実行される。
-        // タスクリストの最期に、この行にやってくる⇒`loop`の先頭に戻る
-    }
-}
+static BLINK: Timer = Timer::new(5, Mode::AutoReload, blink);
 
 static SCHEDULER: RwLock<Scheduler> = RwLock::new(Scheduler::new());
 
+// タスクはstaticに置く(ヒープを使わない)
+static_task!(APP);
+static_task!(APP2);
+static_task!(TIMER_SERVICE);
+
 #[entry]
 fn main() -> ! {
     info!("Program start");
@@ -125,49 +107,29 @@ fn main() -> ! {
 
     info!("system clock = {}", clocks.system_clock.freq().to_kHz()); // 125000kHz = 125MHz
 
+    // Instant::now()のために、TIMERを動かす
+    clock::init(pac.TIMER, &mut pac.RESETS);
+
+    // カーネルの時間の基準は、SysTickかTIMERのどちらか一方を使う
     // ここで core.SYSTをmoveする(同じくSYSTを使っているcortex_m::delay::Delayは同時には使えない)
     // リロード値の最高は 0xff_ffff(24bit)。125000 * 100 = 0xbe_bc20が遅い設定
     // systick::init(&mut core.SYST, clocks.system_clock.freq().to_kHz()); // SysTick = 1ms(1kHz)
+    // systick::init_timer(100_000); // TIMER = 100ms
     systick::init(&mut core.SYST, clocks.system_clock.freq().to_kHz() * 100); // SysTick = 100ms
 
     led::init(pins.gpio25.into_push_pull_output());
 
-    #[link_section = ".uninit.STACKS"]
-    static mut APP_STACK: AlignedStack = AlignedStack(MaybeUninit::uninit());
-    let task = Box::new(Task::new(
-        unsafe { &mut *addr_of_mut!(APP_STACK) },
-        app_main,
-    ));
-    let item: &'static mut ListItem<Task> = Box::leak(Box::new(ListItem::new(*task)));
-    SCHEDULER.write().push_back(item);
+    SCHEDULER.write().push_back(APP.init(app_main));
     info!("task is added");
 
-    #[link_section = ".uninit.STACKS"]
-    static mut APP_STACK2: AlignedStack = AlignedStack(MaybeUninit::uninit());
-    let task2 = Box::new(Task::new(
-        unsafe { &mut *addr_of_mut!(APP_STACK2) },
-        app_main2,
-    ));
-    let item2: &'static mut ListItem<Task> = Box::leak(Box::new(ListItem::new(*task2)));
-    SCHEDULER.write().push_back(item2);
+    SCHEDULER.write().push_back(APP2.init(app_main2));
     info!("task2 is added");
 
-    #[link_section = ".uninit.STACKS"]
-    static mut APP_STACK3: AlignedStack = AlignedStack(MaybeUninit::uninit());
-    let task3 = Box::new(Task::new(
-        unsafe { &mut *addr_of_mut!(APP_STACK3) },
-        app_main3,
-    ));
-    let item3: &'static mut ListItem<Task> = Box::leak(Box::new(ListItem::new(*task3)));
-    SCHEDULER.write().push_back(item3);
-    info!("task3 is added");
-
-    #[link_section = ".uninit.STACKS"]
-    static mut APP_IDLE: AlignedStack = AlignedStack(MaybeUninit::uninit());
-    let idle_task = Box::new(Task::new(unsafe { &mut *addr_of_mut!(APP_IDLE) }, app_idle));
-    let item_idle: &'static mut ListItem<Task> = Box::leak(Box::new(ListItem::new(*idle_task)));
-    SCHEDULER.write().push_back(item_idle);
-    info!("idle_task is added");
+    SCHEDULER
+        .write()
+        .push_back(TIMER_SERVICE.init(timer::service));
+    info!("timer service task is added");
+    BLINK.start(None).unwrap();
 
     SCHEDULER.read().exec();
 }
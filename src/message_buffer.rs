@@ -0,0 +1,208 @@
+// メッセージバッファ
+// 可変長のメッセージを、先頭に長さ(u16, リトルエンディアン)を付けてStreamBufferと同じリングに積む。
+// メッセージは丸ごと書き込まれ、丸ごと読み出される(途中で切れない)。
+//
+// static FRAMES: MessageBuffer<256> = MessageBuffer::new();
+// FRAMES.send_from_isr(&frame)?;                  // 割り込みハンドラ
+// let len = FRAMES.recv(&mut buf, None)?;        // 1メッセージ届くまで待つ
+
+use crate::critical_section::IrqMutex;
+use crate::stream_buffer::ByteRing;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+const LEN_SIZE: usize = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    Full,                  // 待たずに送信しようとしたが、空きが足りない
+    TooLarge,              // メッセージがバッファの容量より大きい
+    BufferTooSmall(usize), // 受信バッファが小さい。メッセージの長さを返す(メッセージは残る)
+}
+
+// ringに1メッセージ積む。空きが足りなければfalse
+fn push_message<const N: usize>(ring: &mut ByteRing<N>, msg: &[u8]) -> bool {
+    if ring.free() < LEN_SIZE + msg.len() {
+        return false;
+    }
+    ring.write(&(msg.len() as u16).to_le_bytes());
+    ring.write(msg);
+    true
+}
+
+// ringから1メッセージ取り出す。メッセージがなければOk(None)
+fn pop_message<const N: usize>(
+    ring: &mut ByteRing<N>,
+    buf: &mut [u8],
+) -> Result<Option<usize>, Error> {
+    let mut header = [0u8; LEN_SIZE];
+    if ring.peek(&mut header) < LEN_SIZE {
+        return Ok(None);
+    }
+    let len = u16::from_le_bytes(header) as usize;
+    if buf.len() < len {
+        return Err(Error::BufferTooSmall(len));
+    }
+    ring.discard(LEN_SIZE);
+    ring.read(&mut buf[..len]);
+    Ok(Some(len))
+}
+
+struct Inner<const N: usize> {
+    ring: ByteRing<N>,
+    readers: WaitList,
+    writers: WaitList,
+}
+
+pub struct MessageBuffer<const N: usize> {
+    inner: IrqMutex<Inner<N>>,
+}
+
+impl<const N: usize> MessageBuffer<N> {
+    pub const fn new() -> Self {
+        // 長さの2バイトしか入らないと、空のメッセージしか送れない
+        const {
+            assert!(
+                N > LEN_SIZE,
+                "MessageBuffer is too small for the length header"
+            )
+        };
+        MessageBuffer {
+            inner: IrqMutex::new(Inner {
+                ring: ByteRing::new(),
+                readers: WaitList::new(),
+                writers: WaitList::new(),
+            }),
+        }
+    }
+
+    // 1メッセージあたり長さの2バイトを余分に使う
+    pub const fn max_message_len(&self) -> usize {
+        let max = N - LEN_SIZE;
+        if max > u16::MAX as usize {
+            u16::MAX as usize
+        } else {
+            max
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().ring.len() == 0
+    }
+
+    // 空きができるまで待って送信する。timeoutはtick数、Noneなら無期限に待つ
+    pub fn send(&self, msg: &[u8], timeout: Option<u32>) -> Result<(), Error> {
+        if msg.len() > self.max_message_len() {
+            return Err(Error::TooLarge);
+        }
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.writers.remove_current();
+                if push_message(&mut inner.ring, msg) {
+                    inner.readers.wake_all();
+                    return Ok(());
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(Error::Timeout);
+                }
+                inner.writers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 1メッセージ届くまで待ってbufに受信し、長さを返す
+    pub fn recv(&self, buf: &mut [u8], timeout: Option<u32>) -> Result<usize, Error> {
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.readers.remove_current();
+                if let Some(len) = pop_message(&mut inner.ring, buf)? {
+                    inner.writers.wake_all();
+                    return Ok(len);
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(Error::Timeout);
+                }
+                inner.readers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 待たずに送信する。割り込みハンドラから呼べる
+    pub fn send_from_isr(&self, msg: &[u8]) -> Result<(), Error> {
+        if msg.len() > self.max_message_len() {
+            return Err(Error::TooLarge);
+        }
+        let mut inner = self.inner.lock();
+        if !push_message(&mut inner.ring, msg) {
+            return Err(Error::Full);
+        }
+        inner.readers.wake_all();
+        Ok(())
+    }
+
+    // 待たずに受信する。メッセージがなければOk(None)。割り込みハンドラから呼べる
+    pub fn recv_from_isr(&self, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+        let mut inner = self.inner.lock();
+        let len = pop_message(&mut inner.ring, buf)?;
+        if len.is_some() {
+            inner.writers.wake_all();
+        }
+        Ok(len)
+    }
+}
+
+impl<const N: usize> Default for MessageBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pop_message, push_message, Error};
+    use crate::stream_buffer::ByteRing;
+
+    #[test]
+    fn test_message_framing() {
+        let mut ring: ByteRing<16> = ByteRing::new();
+        let mut buf = [0u8; 16];
+        assert_eq!(Ok(None), pop_message(&mut ring, &mut buf));
+
+        assert!(push_message(&mut ring, &[1, 2, 3]));
+        assert!(push_message(&mut ring, &[]));
+        assert!(push_message(&mut ring, &[4, 5, 6, 7, 8]));
+        // 2 + 3, 2 + 0, 2 + 5 = 14バイト使用。残り2バイトには1バイトのメッセージも入らない
+        assert!(!push_message(&mut ring, &[9]));
+
+        assert_eq!(Ok(Some(3)), pop_message(&mut ring, &mut buf));
+        assert_eq!([1, 2, 3], buf[..3]);
+        assert_eq!(Ok(Some(0)), pop_message(&mut ring, &mut buf));
+
+        // 受信バッファが小さいとメッセージは残る
+        assert_eq!(
+            Err(Error::BufferTooSmall(5)),
+            pop_message(&mut ring, &mut buf[..4])
+        );
+        assert_eq!(Ok(Some(5)), pop_message(&mut ring, &mut buf));
+        assert_eq!([4, 5, 6, 7, 8], buf[..5]);
+        assert_eq!(Ok(None), pop_message(&mut ring, &mut buf));
+    }
+
+    #[test]
+    fn test_message_wrap_around() {
+        let mut ring: ByteRing<7> = ByteRing::new();
+        let mut buf = [0u8; 8];
+        for i in 0..10u8 {
+            assert!(push_message(&mut ring, &[i, i + 1, i + 2]));
+            assert_eq!(Ok(Some(3)), pop_message(&mut ring, &mut buf));
+            assert_eq!([i, i + 1, i + 2], buf[..3]);
+        }
+    }
+}
@@ -1,72 +1,122 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{self, AtomicBool};
-use rp2040_hal::sio::Spinlock0;
 
-pub struct MutexGuard<'a, T> {
-    lock: &'a Mutex<T>,
+use crate::deadlock;
+use crate::lock_order::MAX_LEVEL;
+use crate::spinlock::{HeldLevel, LockLevel, Spinlock};
+
+// LEVELはロックの順序。ネストして取る場合はlock_after()を使う(spinlock::LockLevel参照)
+pub struct MutexGuard<'a, T, const LEVEL: u8 = 0> {
+    lock: &'a Mutex<T, LEVEL>,
+    _level: HeldLevel,
 }
 
-impl<'a, T> MutexGuard<'a, T> {
-    fn new(lock: &'a Mutex<T>) -> Self {
-        MutexGuard { lock }
+impl<'a, T, const LEVEL: u8> MutexGuard<'a, T, LEVEL> {
+    fn new(lock: &'a Mutex<T, LEVEL>, level: HeldLevel) -> Self {
+        MutexGuard {
+            lock,
+            _level: level,
+        }
+    }
+
+    // Condvarがロックを一旦開放して取り直すために使う
+    pub(crate) fn mutex(guard: &Self) -> &'a Mutex<T, LEVEL> {
+        guard.lock
     }
 }
 
-impl<T> Deref for MutexGuard<'_, T> {
+impl<T, const LEVEL: u8> Deref for MutexGuard<'_, T, LEVEL> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T, const LEVEL: u8> DerefMut for MutexGuard<'_, T, LEVEL> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
 }
 
-impl<T> Drop for MutexGuard<'_, T> {
+impl<T, const LEVEL: u8> Drop for MutexGuard<'_, T, LEVEL> {
     fn drop(&mut self) {
         self.lock.unlock();
     }
 }
 
-pub struct Mutex<T> {
+impl<T, const LEVEL: u8> LockLevel for MutexGuard<'_, T, LEVEL> {
+    const LEVEL: u8 = LEVEL;
+}
+
+pub struct Mutex<T, const LEVEL: u8 = 0> {
     locked: AtomicBool,
+    spinlock: Spinlock,
     data: UnsafeCell<T>,
 }
 
-impl<T> Mutex<T> {
+impl<T, const LEVEL: u8> Mutex<T, LEVEL> {
     pub const fn new(value: T) -> Self {
+        const { assert!(LEVEL <= MAX_LEVEL, "lock level is too high") };
         Self {
             locked: AtomicBool::new(false),
+            spinlock: Spinlock::new(),
             data: UnsafeCell::new(value),
         }
     }
     // 読み書きロック: 本当にロックする
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        // Aquire -> Releaseの順序が保証されるようにバリア命令が出力される
-        // バリア命令はCortex-M0+でも有る
-        while self.locked.load(atomic::Ordering::Acquire) {
-            // 他のスレッドがlockedを開放するまで待つ
-            core::hint::spin_loop();
+    pub fn lock(&self) -> MutexGuard<'_, T, LEVEL> {
+        // 待つ前に確認する(順序が逆ならデッドロックするかもしれない)
+        let level = HeldLevel::enter(LEVEL, true);
+        loop {
+            {
+                // lockedの確認とセットを、このロックに割り当てたハードウェアスピンロックで保護する
+                let _lock = self.spinlock.claim();
+                if !self.locked.load(atomic::Ordering::Acquire) {
+                    self.locked.store(true, atomic::Ordering::Release);
+                    break;
+                }
+                // _lockがここでドロップされ、スピンロックがreleaseされる
+            }
+            deadlock::wait(self.addr());
+            // Aquire -> Releaseの順序が保証されるようにバリア命令が出力される
+            // バリア命令はCortex-M0+でも有る
+            while self.locked.load(atomic::Ordering::Acquire) {
+                // 他のスレッドがlockedを開放するまで待つ
+                core::hint::spin_loop();
+            }
+        }
+        deadlock::acquired(self.addr());
+        MutexGuard::new(self, level)
+    }
+    // heldを保持したままロックする。heldのレベルがこのロックより低くなければコンパイルエラー
+    pub fn lock_after<G: LockLevel>(&self, _held: &G) -> MutexGuard<'_, T, LEVEL> {
+        const { assert!(G::LEVEL < LEVEL, "lock order violation") };
+        self.lock()
+    }
+    // ロックが取れなければ待たずにNoneを返す
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T, LEVEL>> {
+        let _lock = self.spinlock.try_claim()?;
+        if self.locked.load(atomic::Ordering::Acquire) {
+            return None;
         }
-        // self.lockedの操作をSpinLock0で保護する
-        let _lock = Spinlock0::claim();
         self.locked.store(true, atomic::Ordering::Release);
-        MutexGuard::new(self)
-        // _lockがここでドロップされ、SpinLock0がreleaseされる
+        deadlock::acquired(self.addr());
+        Some(MutexGuard::new(self, HeldLevel::enter(LEVEL, false)))
     }
     fn unlock(&self) {
         if !self.locked.load(atomic::Ordering::Acquire) {
             return;
         }
-        let _lock = Spinlock0::claim();
+        let _lock = self.spinlock.claim();
         self.locked.store(false, atomic::Ordering::Release);
+        deadlock::released(self.addr());
+    }
+    fn addr(&self) -> usize {
+        self as *const Self as usize
     }
 }
 
-unsafe impl<T> Sync for Mutex<T> {}
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
-unsafe impl<T> Send for MutexGuard<'_, T> where T: Send {}
+unsafe impl<T, const LEVEL: u8> Sync for Mutex<T, LEVEL> {}
+unsafe impl<T, const LEVEL: u8> Sync for MutexGuard<'_, T, LEVEL> where T: Sync {}
+unsafe impl<T, const LEVEL: u8> Send for MutexGuard<'_, T, LEVEL> where T: Send {}
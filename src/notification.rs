@@ -0,0 +1,82 @@
+// タスクの通知値。notify.rsが使う
+// タスクには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/notification.rs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyAction {
+    SetBits,   // 通知値にvalueをOR
+    Increment, // 通知値を1増やす(valueは使わない)。カウンティングセマフォとして使える
+    Overwrite, // 通知値をvalueで上書き
+}
+
+pub struct Notification {
+    value: u32,
+    pending: bool,
+}
+
+impl Notification {
+    pub const fn new() -> Self {
+        Notification {
+            value: 0,
+            pending: false,
+        }
+    }
+
+    pub fn apply(&mut self, value: u32, action: NotifyAction) {
+        match action {
+            NotifyAction::SetBits => self.value |= value,
+            NotifyAction::Increment => self.value = self.value.wrapping_add(1),
+            NotifyAction::Overwrite => self.value = value,
+        }
+        self.pending = true;
+    }
+
+    // 通知が届いていれば値を返して、通知値を0に戻す
+    pub fn take(&mut self) -> Option<u32> {
+        if !self.pending {
+            return None;
+        }
+        self.pending = false;
+        Some(core::mem::take(&mut self.value))
+    }
+}
+
+impl Default for Notification {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Notification, NotifyAction};
+
+    #[test]
+    fn test_actions() {
+        let mut n = Notification::new();
+        assert_eq!(None, n.take());
+        n.apply(0b01, NotifyAction::SetBits);
+        n.apply(0b10, NotifyAction::SetBits);
+        assert_eq!(Some(0b11), n.take());
+        // 受け取ったら0に戻る
+        assert_eq!(None, n.take());
+        n.apply(7, NotifyAction::Increment);
+        n.apply(7, NotifyAction::Increment);
+        assert_eq!(Some(2), n.take());
+        n.apply(3, NotifyAction::SetBits);
+        n.apply(5, NotifyAction::Overwrite);
+        assert_eq!(Some(5), n.take());
+    }
+
+    #[test]
+    fn test_zero_value() {
+        // 値が0でも通知は届く
+        let mut n = Notification::new();
+        n.apply(0, NotifyAction::Overwrite);
+        assert_eq!(Some(0), n.take());
+        n.apply(u32::MAX, NotifyAction::Overwrite);
+        n.apply(0, NotifyAction::Increment);
+        assert_eq!(Some(0), n.take());
+    }
+}
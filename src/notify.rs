@@ -0,0 +1,46 @@
+// タスク通知
+// 各タスクが1ワードの通知値を持ち、他のタスクや割り込みハンドラから直接書き込んで起こす。
+// 送り手と受け手が1対1の場合、QueueやEventGroupを用意するより軽い。
+//
+// let handle = SCHEDULER.write().push_back(item);
+// notify::notify(handle, 1 << 3, NotifyAction::SetBits); // 送り手
+// let bits = notify::wait_notification(Some(10))?;      // 受け手(handleのタスク)
+
+pub use crate::notification::NotifyAction;
+use crate::systick;
+use crate::task::TaskHandle;
+use crate::wait_list;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+}
+
+pub fn notify(task: TaskHandle, value: u32, action: NotifyAction) {
+    task.notification().lock().apply(value, action);
+    task.wake();
+}
+
+// 通知値はクリティカルセクションで保護しているので、割り込みハンドラからも呼べる
+pub fn notify_from_isr(task: TaskHandle, value: u32, action: NotifyAction) {
+    notify(task, value, action);
+}
+
+// 実行中のタスク宛の通知を待つ。timeoutはtick数、Noneなら無期限に待つ
+pub fn wait_notification(timeout: Option<u32>) -> Result<u32, Error> {
+    let me = TaskHandle::current().expect("wait_notification() is called outside of a task");
+    let deadline = timeout.map(systick::deadline);
+    loop {
+        // 先に待ち状態にしてから確認する。確認後に通知されても起床は失われない
+        me.block(deadline);
+        if let Some(value) = me.notification().lock().take() {
+            me.unblock();
+            return Ok(value);
+        }
+        if deadline.is_some_and(systick::is_expired) {
+            me.unblock();
+            return Err(Error::Timeout);
+        }
+        wait_list::suspend();
+    }
+}
@@ -0,0 +1,103 @@
+// 再帰ロックの所有者とネストの深さ。RecursiveMutexの中身
+// 所有者の型(RecursiveMutexではOption<TaskHandle>)には依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/owner_count.rs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Release {
+    Nested,   // まだ保持している
+    Released, // 最後のunlockで開放した
+    NotOwner, // 保持していない
+}
+
+pub struct OwnerCount<O> {
+    owner: Option<O>, // countが0ならNone
+    count: u32,
+}
+
+impl<O: Copy + PartialEq> OwnerCount<O> {
+    pub const fn new() -> Self {
+        OwnerCount {
+            owner: None,
+            count: 0,
+        }
+    }
+
+    // 誰も保持していないか、meが保持していれば取れる
+    pub fn acquire(&mut self, me: O) -> bool {
+        match self.owner {
+            None => {
+                self.owner = Some(me);
+                self.count = 1;
+                true
+            }
+            Some(owner) if owner == me => {
+                self.count += 1;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    // ネストを1段戻す
+    pub fn release(&mut self, me: O) -> Release {
+        if self.owner != Some(me) {
+            return Release::NotOwner;
+        }
+        self.count -= 1;
+        if self.count > 0 {
+            return Release::Nested;
+        }
+        self.owner = None;
+        Release::Released
+    }
+
+    pub fn owner(&self) -> Option<O> {
+        self.owner
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
+
+impl<O: Copy + PartialEq> Default for OwnerCount<O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OwnerCount, Release};
+
+    #[test]
+    fn test_nesting() {
+        let mut lock: OwnerCount<u32> = OwnerCount::new();
+        assert!(lock.acquire(1));
+        assert!(lock.acquire(1));
+        assert_eq!(2, lock.count());
+        // 他の所有者は取れないし、開放もできない
+        assert!(!lock.acquire(2));
+        assert_eq!(Release::NotOwner, lock.release(2));
+        assert_eq!(Release::Nested, lock.release(1));
+        assert_eq!(Some(1), lock.owner());
+        assert_eq!(Release::Released, lock.release(1));
+        assert_eq!(None, lock.owner());
+        // 開放したあとはunlockできない
+        assert_eq!(Release::NotOwner, lock.release(1));
+        assert!(lock.acquire(2));
+        assert_eq!(Some(2), lock.owner());
+    }
+
+    // 所有者がOption(RecursiveMutexのカーネル = None)でも区別する
+    #[test]
+    fn test_kernel_owner() {
+        let mut lock: OwnerCount<Option<u32>> = OwnerCount::new();
+        assert!(lock.acquire(None));
+        assert!(!lock.acquire(Some(1)));
+        assert_eq!(Some(None), lock.owner());
+        assert_eq!(Release::NotOwner, lock.release(Some(1)));
+        assert_eq!(Release::Released, lock.release(None));
+    }
+}
@@ -0,0 +1,164 @@
+// 固定長ブロックのメモリプール
+// 同じ型のブロックをN個staticに確保しておき、確保も開放も一定時間で終わる。ヒープを断片化させない。
+// 確保したブロックはPoolBoxで受け取り、dropするとプールに戻る。
+// 空きがなければ、開放されるまで待つこともできる。割り込みハンドラからは_from_isrを使う。
+//
+// static BUFFERS: Pool<[u8; 64], 8> = Pool::new();
+// let mut buf = BUFFERS.alloc([0; 64], Some(10))?; // 最大10tick待つ
+// buf[0] = 1;
+// drop(buf); // プールに戻る
+
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use crate::critical_section::IrqMutex;
+use crate::free_list::FreeList;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AllocError<T> {
+    Empty(T), // 空きがない(待たない場合)
+    Timeout(T),
+}
+
+struct Inner<const N: usize> {
+    free: FreeList<N>,
+    waiters: WaitList, // 空きを待っているタスク
+}
+
+pub struct Pool<T, const N: usize> {
+    blocks: [UnsafeCell<MaybeUninit<T>>; N],
+    inner: IrqMutex<Inner<N>>,
+}
+
+// PoolBoxがNによらずにブロックを返すため
+trait Release {
+    fn release(&self, index: usize);
+}
+
+impl<T, const N: usize> Pool<T, N> {
+    pub const fn new() -> Self {
+        Pool {
+            blocks: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            inner: IrqMutex::new(Inner {
+                free: FreeList::new(),
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    // 空いているブロックの数
+    pub fn available(&self) -> usize {
+        self.inner.lock().free.len()
+    }
+
+    // 空きができるまで待って、valueを入れたブロックを確保する。timeoutはtick数、Noneなら無期限に待つ
+    pub fn alloc(
+        &'static self,
+        value: T,
+        timeout: Option<u32>,
+    ) -> Result<PoolBox<T>, AllocError<T>> {
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.waiters.remove_current();
+                if let Some(index) = inner.free.pop() {
+                    return Ok(self.make_box(index, value));
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(AllocError::Timeout(value));
+                }
+                inner.waiters.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 待たずに確保する
+    pub fn try_alloc(&'static self, value: T) -> Result<PoolBox<T>, AllocError<T>> {
+        let index = self.inner.lock().free.pop();
+        match index {
+            Some(index) => Ok(self.make_box(index, value)),
+            None => Err(AllocError::Empty(value)),
+        }
+    }
+
+    // 割り込みハンドラから確保する。割り込みハンドラは待てないので、空きがなければEmptyを返す
+    pub fn alloc_from_isr(&'static self, value: T) -> Result<PoolBox<T>, AllocError<T>> {
+        self.try_alloc(value)
+    }
+
+    fn make_box(&'static self, index: usize, value: T) -> PoolBox<T> {
+        let block = unsafe { &mut *self.blocks[index].get() };
+        PoolBox {
+            value: NonNull::from(block.write(value)),
+            index,
+            pool: self,
+        }
+    }
+}
+
+impl<T, const N: usize> Release for Pool<T, N> {
+    // 開放はどこからでもできる(割り込みハンドラからも)
+    fn release(&self, index: usize) {
+        let mut inner = self.inner.lock();
+        inner.free.push(index);
+        inner.waiters.wake_one();
+    }
+}
+
+impl<T, const N: usize> Default for Pool<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Pool<T, N> {}
+
+// プールのブロックを指すポインタ。dropすると中身をdropしてブロックをプールに戻す
+pub struct PoolBox<T: 'static> {
+    value: NonNull<T>,
+    index: usize,
+    pool: &'static dyn Release,
+}
+
+impl<T> PoolBox<T> {
+    // 中身を取り出して、ブロックをプールに戻す
+    pub fn into_inner(this: PoolBox<T>) -> T {
+        let value = unsafe { this.value.as_ptr().read() };
+        this.pool.release(this.index);
+        mem::forget(this);
+        value
+    }
+}
+
+impl<T> Deref for PoolBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PoolBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T> Drop for PoolBox<T> {
+    fn drop(&mut self) {
+        unsafe { self.value.as_ptr().drop_in_place() };
+        self.pool.release(self.index);
+    }
+}
+
+unsafe impl<T: Send> Send for PoolBox<T> {}
+unsafe impl<T: Sync> Sync for PoolBox<T> {}
@@ -0,0 +1,397 @@
+// 省電力状態
+// アイドルタスクがdeep sleepを選んだとき(idle::Policy)に、どこまで深く眠るかを決める。
+//   Run:     tickless idleだけ(クロックは止めない)
+//   Sleep:   SLEEPDEEPで、眠っている間は必要なクロック(TIMER、GPIOなど)以外を止める。TIMERのアラームで起きる
+//   Dormant: XOSCを止める。TIMERも止まるので、期限が1つもなく、GPIOの起床要因があるときだけ
+//            RTCがXOSC以外のクロック(GPINの32.768kHzなど)で動いていれば、止まっていた時間を
+//            RTCで測ってtickとInstantに足す(RTCとのずれは1秒未満)。RTCも止まっていれば時間は進まない
+// タスクはStayAwakeを持っている間、それより深い状態に入らないようにできる(通信中など)。
+// どちらもカーネルの時間の基準がTIMER(systick::init_timer)のときだけ使う。
+//
+// let _awake = StayAwake::new(PowerState::Run); // dropするまでSleep/Dormantに入らない
+// power::wake_on_gpio(15, Wake::EdgeLow);       // GP15の立ち下がりでDormantから起きる
+
+use cortex_m::peripheral::NVIC;
+#[cfg(feature = "rtc-irq")]
+use rp2040_hal::pac::interrupt;
+use rp2040_hal::pac::{self, pll_sys};
+
+use crate::clock::Instant;
+use crate::critical_section::IrqMutex;
+use crate::tick::RtcAnchor;
+
+// 浅い順
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    Run,
+    Sleep,
+    Dormant,
+}
+
+// Dormantから起きるGPIOの条件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    LevelLow,
+    LevelHigh,
+    EdgeLow,
+    EdgeHigh,
+}
+
+const GPIO_PINS: u8 = 30;
+
+// 持っているStayAwakeの数
+struct Locks {
+    run: u32,   // Runより深く眠らない
+    sleep: u32, // Sleepより深く眠らない
+}
+
+impl Locks {
+    // 入ってよい一番深い状態
+    fn allowed(&self) -> PowerState {
+        if self.run > 0 {
+            PowerState::Run
+        } else if self.sleep > 0 {
+            PowerState::Sleep
+        } else {
+            PowerState::Dormant
+        }
+    }
+
+    fn count(&mut self, limit: PowerState) -> Option<&mut u32> {
+        match limit {
+            PowerState::Run => Some(&mut self.run),
+            PowerState::Sleep => Some(&mut self.sleep),
+            PowerState::Dormant => None,
+        }
+    }
+}
+
+struct WakeSources {
+    gpio: u32, // Dormantから起きるGPIOのビットマップ
+    rtc: bool, // SleepでRTCのクロックを止めない
+}
+
+static LOCKS: IrqMutex<Locks> = IrqMutex::new(Locks { run: 0, sleep: 0 });
+static WAKE: IrqMutex<WakeSources> = IrqMutex::new(WakeSources {
+    gpio: 0,
+    rtc: false,
+});
+// Dormantで止まっていた時間を測るRTCとTIMERの基準
+static RTC_ANCHOR: IrqMutex<RtcAnchor> = IrqMutex::new(RtcAnchor::new());
+// Sleepでも止めないクロック(SLEEP_EN0, SLEEP_EN1)。アプリケーションが使うペリフェラルを追加する
+static KEEP_CLOCKS: IrqMutex<(u32, u32)> = IrqMutex::new((0, 0));
+
+// dropするまで、limitより深い状態に入らない
+pub struct StayAwake {
+    limit: PowerState,
+}
+
+impl StayAwake {
+    pub fn new(limit: PowerState) -> Self {
+        if let Some(count) = LOCKS.lock().count(limit) {
+            *count += 1;
+        }
+        StayAwake { limit }
+    }
+}
+
+impl Drop for StayAwake {
+    fn drop(&mut self) {
+        if let Some(count) = LOCKS.lock().count(self.limit) {
+            *count -= 1;
+        }
+    }
+}
+
+pub fn allowed() -> PowerState {
+    LOCKS.lock().allowed()
+}
+
+// pinがwakeになるとDormantから起きる
+pub fn wake_on_gpio(pin: u8, wake: Wake) {
+    assert!(pin < GPIO_PINS);
+    let (n, bit) = wake_bit(pin, wake);
+    let io = unsafe { &*pac::IO_BANK0::PTR };
+    io.dormant_wake_inte(n)
+        .modify(|r, w| unsafe { w.bits(r.bits() | bit) });
+    WAKE.lock().gpio |= 1 << pin;
+}
+
+pub fn clear_gpio_wake(pin: u8) {
+    assert!(pin < GPIO_PINS);
+    let io = unsafe { &*pac::IO_BANK0::PTR };
+    let (n, _) = wake_bit(pin, Wake::LevelLow);
+    let mask = 0xf << ((pin % 8) * 4);
+    io.dormant_wake_inte(n)
+        .modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+    WAKE.lock().gpio &= !(1 << pin);
+}
+
+// RTCのアラームでSleepから起きる。アラームはrp2040_hal::rtc::RealTimeClockで設定する
+// アラームは1回だけ(RTC_IRQで止める)
+pub fn wake_on_rtc(enable: bool) {
+    WAKE.lock().rtc = enable;
+    if enable {
+        unsafe { NVIC::unmask(pac::Interrupt::RTC_IRQ) };
+    } else {
+        NVIC::mask(pac::Interrupt::RTC_IRQ);
+    }
+}
+
+// Sleepの間も止めないクロックを追加する(SLEEP_EN0, SLEEP_EN1のビット)
+pub fn keep_clocks_in_sleep(en0: u32, en1: u32) {
+    let mut keep = KEEP_CLOCKS.lock();
+    keep.0 |= en0;
+    keep.1 |= en1;
+}
+
+// DORMANT_WAKE_INTEのレジスタ番号とビット。1ピン4ビットで、1レジスタに8ピン
+fn wake_bit(pin: u8, wake: Wake) -> (usize, u32) {
+    let shift = (pin % 8) * 4 + wake as u8;
+    ((pin / 8) as usize, 1 << shift)
+}
+
+// 期限までticks(期限がなければu32::MAX)あるとき、入る状態
+fn choose(allowed: PowerState, ticks: u32, gpio_wake: bool) -> PowerState {
+    match allowed {
+        PowerState::Dormant if ticks == u32::MAX && gpio_wake => PowerState::Dormant,
+        PowerState::Run => PowerState::Run,
+        _ => PowerState::Sleep,
+    }
+}
+
+// アイドル中に、割り込み禁止で呼ぶ
+pub(crate) fn choose_state(ticks: u32) -> PowerState {
+    choose(allowed(), ticks, WAKE.lock().gpio != 0)
+}
+
+// Sleepの準備。SLEEPDEEPでwfiすると、ここで許可したクロック以外が止まる
+// 起きるとハードウェアが元のクロック(WAKE_EN)に戻す
+pub(crate) fn gate_clocks() {
+    let clocks = unsafe { &*pac::CLOCKS::PTR };
+    let (en0, en1) = *KEEP_CLOCKS.lock();
+    let rtc = WAKE.lock().rtc;
+    clocks.sleep_en0().write(|w| {
+        w.clk_sys_clocks().set_bit();
+        w.clk_sys_busfabric().set_bit();
+        w.clk_sys_io().set_bit();
+        w.clk_sys_pads().set_bit();
+        w.clk_sys_pll_sys().set_bit();
+        w.clk_sys_pll_usb().set_bit();
+        w.clk_sys_psm().set_bit();
+        w.clk_sys_resets().set_bit();
+        w.clk_sys_rosc().set_bit();
+        w.clk_sys_vreg_and_chip_reset().set_bit();
+        if rtc {
+            w.clk_sys_rtc().set_bit();
+            w.clk_rtc_rtc().set_bit();
+        }
+        w
+    });
+    clocks.sleep_en1().write(|w| {
+        w.clk_sys_timer().set_bit();
+        w.clk_sys_watchdog().set_bit(); // TIMERの1MHzはwatchdogのtickから作る
+        w.clk_sys_xosc().set_bit();
+        w
+    });
+    clocks
+        .sleep_en0()
+        .modify(|r, w| unsafe { w.bits(r.bits() | en0) });
+    clocks
+        .sleep_en1()
+        .modify(|r, w| unsafe { w.bits(r.bits() | en1) });
+}
+
+// XOSCを止めて、GPIOで起きるまで待つ。止まっていた時間(マイクロ秒、RTCで測れなければ0)を返す
+// RTCは秒単位なので、1回ごとに前後の差をとると最大1秒ずつ遅れが積み重なる。
+// 最初のDormantのRTCとTIMERを基準にして合わせる(tick::RtcAnchor)ので、TIMERとRTCのずれは
+// 何回眠っても1秒未満。RTCとXOSCの水晶のずれや、RTCを設定し直したときは基準を取り直す
+// clk_sysをclk_ref(XOSC)に切り替えてから止め、起きたらXOSCが安定するのを待って、
+// PLLを同じ設定で初期化し直してから元に戻す
+pub(crate) fn dormant() -> u64 {
+    let clocks = unsafe { &*pac::CLOCKS::PTR };
+    let xosc = unsafe { &*pac::XOSC::PTR };
+    let pll_sys = unsafe { &*pac::PLL_SYS::PTR };
+    let pll_usb = unsafe { &*pac::PLL_USB::PTR };
+    let io = unsafe { &*pac::IO_BANK0::PTR };
+
+    let sys_ctrl = clocks.clk_sys_ctrl().read().bits();
+    let sys_selected = clocks.clk_sys_selected().read().bits();
+    let plls = (PllConfig::save(pll_sys), PllConfig::save(pll_usb));
+    clocks.clk_sys_ctrl().modify(|_, w| w.src().clk_ref());
+    while clocks.clk_sys_selected().read().bits() != 1 {}
+    let before = rtc_now();
+    let now = Instant::now().as_micros();
+
+    // "coma"を書くとXOSCが止まる。GPIOで起きると続きから実行する
+    xosc.dormant().write(|w| unsafe { w.bits(0x636f_6d61) });
+    while xosc.status().read().stable().bit_is_clear() {}
+    plls.0.restore(pll_sys);
+    plls.1.restore(pll_usb);
+
+    clocks.clk_sys_ctrl().write(|w| unsafe { w.bits(sys_ctrl) });
+    while clocks.clk_sys_selected().read().bits() != sys_selected {}
+
+    // エッジの起床要因は自分では消えない
+    let pins = WAKE.lock().gpio;
+    for n in 0..(GPIO_PINS as usize).div_ceil(8) {
+        let edges = (0..8)
+            .filter(|i| pins & (1 << (n * 8 + i)) != 0)
+            .fold(0, |acc, i| acc | (0b1100 << (i * 4)));
+        if edges != 0 {
+            io.intr(n).write(|w| unsafe { w.bits(edges) });
+        }
+    }
+
+    match (before, rtc_now()) {
+        (Some(before), Some(after)) => RTC_ANCHOR.lock().advance(before, after, now),
+        _ => 0,
+    }
+}
+
+// PLLの設定。XOSCを止めるとロックが外れるので、起きたら同じ設定で初期化し直す
+struct PllConfig {
+    cs: u32,
+    fbdiv: u32,
+    prim: u32,
+    pwr: u32,
+}
+
+impl PllConfig {
+    const PD: u32 = 1 << 0;
+    const POSTDIVPD: u32 = 1 << 3;
+    const VCOPD: u32 = 1 << 5;
+    const ALL_PD: u32 = 0x2d;
+    const LOCK: u32 = 1 << 31;
+
+    fn save(pll: &pll_sys::RegisterBlock) -> Self {
+        PllConfig {
+            cs: pll.cs().read().bits() & !Self::LOCK,
+            fbdiv: pll.fbdiv_int().read().bits(),
+            prim: pll.prim().read().bits(),
+            pwr: pll.pwr().read().bits(),
+        }
+    }
+
+    // データシートの手順: 電源を切って分周比を書き、VCOを起こしてロックを待ってからポストディバイダを起こす
+    // 使っていなかった(電源を切っていた)PLLはそのまま
+    fn restore(&self, pll: &pll_sys::RegisterBlock) {
+        if self.pwr & (Self::PD | Self::VCOPD) != 0 {
+            return;
+        }
+        pll.pwr().write(|w| unsafe { w.bits(Self::ALL_PD) });
+        pll.cs().write(|w| unsafe { w.bits(self.cs) });
+        pll.fbdiv_int().write(|w| unsafe { w.bits(self.fbdiv) });
+        pll.pwr()
+            .write(|w| unsafe { w.bits(self.pwr | Self::POSTDIVPD) });
+        while pll.cs().read().lock().bit_is_clear() {}
+        pll.prim().write(|w| unsafe { w.bits(self.prim) });
+        pll.pwr().write(|w| unsafe { w.bits(self.pwr) });
+    }
+}
+
+// RTCの今の日時を秒にする。RTCが動いていなければNone
+fn rtc_now() -> Option<u64> {
+    let rtc = unsafe { &*pac::RTC::PTR };
+    if rtc.ctrl().read().rtc_active().bit_is_clear() {
+        return None;
+    }
+    // RTC_0を先に読む(RTC_1はそのときの値に固定される)
+    let rtc_0 = rtc.rtc_0().read().bits();
+    let rtc_1 = rtc.rtc_1().read().bits();
+    Some(rtc_seconds(rtc_1, rtc_0))
+}
+
+// RTC_1(年月日)とRTC_0(時分秒)を、0年3月1日からの秒数にする。経過時間の計算にだけ使う
+fn rtc_seconds(rtc_1: u32, rtc_0: u32) -> u64 {
+    let (year, month, day) = ((rtc_1 >> 12) & 0xfff, (rtc_1 >> 8) & 0xf, rtc_1 & 0x1f);
+    let (hour, min, sec) = ((rtc_0 >> 16) & 0x1f, (rtc_0 >> 8) & 0x3f, rtc_0 & 0x3f);
+    // 3月始まりにすると、うるう日が年の最後に来る
+    let (y, m) = if month <= 2 {
+        (year as u64 + 399, month as u64 + 9)
+    } else {
+        (year as u64 + 400, month as u64 - 3)
+    };
+    let days = y * 365 + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day as u64 - 1;
+    ((days * 24 + hour as u64) * 60 + min as u64) * 60 + sec as u64
+}
+
+// RTCのアラームで起きたら、アラームを止める(起こすだけで、タスクは起こさない)
+// feature "rtc-irq"(デフォルト)を無効にすると、RTC_IRQはアプリケーションが定義できる。
+// その場合、wake_on_rtc()を使うならアプリケーションのRTC_IRQからon_rtc_irq()を呼ぶこと
+#[cfg(feature = "rtc-irq")]
+#[interrupt]
+fn RTC_IRQ() {
+    on_rtc_irq();
+}
+
+pub fn on_rtc_irq() {
+    let rtc = unsafe { &*pac::RTC::PTR };
+    rtc.irq_setup_0().modify(|_, w| w.match_ena().clear_bit());
+    while rtc.irq_setup_0().read().match_active().bit_is_set() {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::{choose, rtc_seconds, wake_bit, Locks, PowerState, Wake};
+
+    fn rtc(year: u32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> u64 {
+        rtc_seconds(year << 12 | month << 8 | day, hour << 16 | min << 8 | sec)
+    }
+
+    #[test]
+    fn test_locks() {
+        let mut locks = Locks { run: 0, sleep: 0 };
+        assert_eq!(PowerState::Dormant, locks.allowed());
+        *locks.count(PowerState::Sleep).unwrap() += 1;
+        assert_eq!(PowerState::Sleep, locks.allowed());
+        *locks.count(PowerState::Run).unwrap() += 1;
+        assert_eq!(PowerState::Run, locks.allowed());
+        *locks.count(PowerState::Sleep).unwrap() -= 1;
+        assert_eq!(PowerState::Run, locks.allowed());
+        assert!(locks.count(PowerState::Dormant).is_none());
+    }
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(PowerState::Run, choose(PowerState::Run, u32::MAX, true));
+        assert_eq!(PowerState::Sleep, choose(PowerState::Sleep, u32::MAX, true));
+        assert_eq!(
+            PowerState::Dormant,
+            choose(PowerState::Dormant, u32::MAX, true)
+        );
+        // 期限があるか、GPIOの起床要因がなければ、TIMERで起きられるSleepまで
+        assert_eq!(PowerState::Sleep, choose(PowerState::Dormant, 100, true));
+        assert_eq!(
+            PowerState::Sleep,
+            choose(PowerState::Dormant, u32::MAX, false)
+        );
+    }
+
+    #[test]
+    fn test_wake_bit() {
+        assert_eq!((0, 1 << 0), wake_bit(0, Wake::LevelLow));
+        assert_eq!((0, 1 << 3), wake_bit(0, Wake::EdgeHigh));
+        assert_eq!((1, 1 << 30), wake_bit(15, Wake::EdgeLow));
+        assert_eq!((3, 1 << 21), wake_bit(29, Wake::LevelHigh));
+    }
+
+    #[test]
+    fn test_rtc_seconds() {
+        assert_eq!(1, rtc(2024, 1, 1, 0, 0, 1) - rtc(2024, 1, 1, 0, 0, 0));
+        assert_eq!(86_400, rtc(2024, 3, 1, 0, 0, 0) - rtc(2024, 2, 29, 0, 0, 0));
+        // うるう年とそうでない年、月と年をまたぐ
+        assert_eq!(
+            2 * 86_400,
+            rtc(2024, 3, 1, 0, 0, 0) - rtc(2024, 2, 28, 0, 0, 0)
+        );
+        assert_eq!(86_400, rtc(2023, 3, 1, 0, 0, 0) - rtc(2023, 2, 28, 0, 0, 0));
+        assert_eq!(86_400, rtc(2100, 3, 1, 0, 0, 0) - rtc(2100, 2, 28, 0, 0, 0));
+        assert_eq!(5, rtc(2025, 1, 1, 0, 0, 2) - rtc(2024, 12, 31, 23, 59, 57));
+        assert_eq!(
+            366 * 86_400,
+            rtc(2025, 1, 1, 0, 0, 0) - rtc(2024, 1, 1, 0, 0, 0)
+        );
+        assert_eq!(3_723, rtc(2024, 6, 1, 1, 2, 3) - rtc(2024, 6, 1, 0, 0, 0));
+    }
+}
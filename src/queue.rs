@@ -0,0 +1,170 @@
+// 固定長のメッセージキュー
+// staticに確保できるので、グローバルアロケータがなくても使える
+//
+// static SAMPLES: Queue<u16, 8> = Queue::new();
+// SAMPLES.send(value, Some(10))?; // 最大10tick待つ
+// let value = SAMPLES.recv(None)?; // 受信するまで待つ
+//
+// restricted()で作ると、ケーパビリティ(Object::Queue)を持つタスクだけが使える。
+// 送信にはRights::SEND、受信にはRights::RECEIVEが必要。
+
+use crate::capability::{self, Object, Rights};
+use crate::critical_section::IrqMutex;
+use crate::fifo::Fifo;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError<T> {
+    Full(T),
+    Timeout(T),
+    NoCapability(T), // ケーパビリティがないか、権限が足りない
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    Empty,
+    Timeout,
+    NoCapability,
+}
+
+struct Inner<T, const N: usize> {
+    buf: Fifo<T, N>,
+    senders: WaitList,   // 空きを待っているタスク
+    receivers: WaitList, // データを待っているタスク
+}
+
+pub struct Queue<T, const N: usize> {
+    inner: IrqMutex<Inner<T, N>>,
+    restricted: bool, // ケーパビリティを確かめる
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            inner: IrqMutex::new(Inner {
+                buf: Fifo::new(),
+                senders: WaitList::new(),
+                receivers: WaitList::new(),
+            }),
+            restricted: false,
+        }
+    }
+
+    // ケーパビリティを持つタスクだけが使えるキュー
+    pub const fn restricted() -> Self {
+        let mut queue = Self::new();
+        queue.restricted = true;
+        queue
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    // 空きができるまで待って送信する。timeoutはtick数、Noneなら無期限に待つ
+    pub fn send(&self, value: T, timeout: Option<u32>) -> Result<(), SendError<T>> {
+        if !self.permit(Rights::SEND) {
+            return Err(SendError::NoCapability(value));
+        }
+        let deadline = timeout.map(systick::deadline);
+        let mut value = value;
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.senders.remove_current();
+                match inner.buf.push(value) {
+                    Ok(()) => {
+                        inner.receivers.wake_one();
+                        return Ok(());
+                    }
+                    Err(v) => value = v,
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(SendError::Timeout(value));
+                }
+                inner.senders.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // データが届くまで待って受信する。timeoutはtick数、Noneなら無期限に待つ
+    pub fn recv(&self, timeout: Option<u32>) -> Result<T, RecvError> {
+        if !self.permit(Rights::RECEIVE) {
+            return Err(RecvError::NoCapability);
+        }
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.receivers.remove_current();
+                if let Some(value) = inner.buf.pop() {
+                    inner.senders.wake_one();
+                    return Ok(value);
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(RecvError::Timeout);
+                }
+                inner.receivers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 待たずに送信する
+    pub fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        if !self.permit(Rights::SEND) {
+            return Err(SendError::NoCapability(value));
+        }
+        let mut inner = self.inner.lock();
+        inner.buf.push(value).map_err(SendError::Full)?;
+        inner.receivers.wake_one();
+        Ok(())
+    }
+
+    // 待たずに受信する
+    pub fn try_recv(&self) -> Result<T, RecvError> {
+        if !self.permit(Rights::RECEIVE) {
+            return Err(RecvError::NoCapability);
+        }
+        let mut inner = self.inner.lock();
+        let value = inner.buf.pop().ok_or(RecvError::Empty)?;
+        inner.senders.wake_one();
+        Ok(value)
+    }
+
+    // 割り込みハンドラから送信する。割り込みハンドラは待てないので、いっぱいならFullを返す
+    pub fn send_from_isr(&self, value: T) -> Result<(), SendError<T>> {
+        self.try_send(value)
+    }
+
+    // 割り込みハンドラから受信する。空ならEmptyを返す
+    pub fn recv_from_isr(&self) -> Result<T, RecvError> {
+        self.try_recv()
+    }
+
+    fn permit(&self, rights: Rights) -> bool {
+        !self.restricted || capability::require(Object::queue(self), rights).is_ok()
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
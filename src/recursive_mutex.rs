@@ -0,0 +1,123 @@
+// 再帰ロック可能なMutex
+// ロックを保持しているタスクは、開放する前に同じロックを何度でも取れる。
+// 最後のunlockで開放され、待っているタスクが起こされる。
+// 同じバスロックを取りながらドライバ同士が呼び合うような場合に使う。
+// 複数のガードが同時に存在するので、中身は&Tでしか参照できない(書き換えるにはCell/RefCellを使う)。
+//
+// static BUS: RecursiveMutex<RefCell<Bus>> = RecursiveMutex::new(RefCell::new(Bus::new()));
+// let bus = BUS.lock();
+// read_sensor(); // この中でもBUS.lock()できる
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::Deref;
+
+use crate::mutex::Mutex;
+use crate::owner_count::{OwnerCount, Release};
+use crate::task::TaskHandle;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    NotOwner, // ロックを保持していないタスクがunlockしようとした
+}
+
+struct State {
+    owner: OwnerCount<Option<TaskHandle>>, // Noneはカーネル(タスク以外)
+    waiters: WaitList,
+}
+
+pub struct RecursiveMutex<T> {
+    state: Mutex<State>,
+    data: UnsafeCell<T>,
+}
+
+// 取ったタスクの中でdropしなければいけないので、Sendではない
+pub struct RecursiveMutexGuard<'a, T> {
+    lock: &'a RecursiveMutex<T>,
+    marker: PhantomData<*const ()>,
+}
+
+impl<T> RecursiveMutex<T> {
+    pub const fn new(value: T) -> Self {
+        RecursiveMutex {
+            state: Mutex::new(State {
+                owner: OwnerCount::new(),
+                waiters: WaitList::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> RecursiveMutexGuard<'_, T> {
+        self.raw_lock();
+        RecursiveMutexGuard {
+            lock: self,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<RecursiveMutexGuard<'_, T>> {
+        if !self.raw_try_lock() {
+            return None;
+        }
+        Some(RecursiveMutexGuard {
+            lock: self,
+            marker: PhantomData,
+        })
+    }
+
+    // ガードを使わずにロックする。raw_unlock()と対にする
+    pub fn raw_lock(&self) {
+        loop {
+            {
+                let mut state = self.state.lock();
+                state.waiters.remove_current();
+                if state.owner.acquire(TaskHandle::current()) {
+                    return;
+                }
+                // 他のタスクが保持している。開放されるまで待つ
+                state.waiters.block_current(None);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    pub fn raw_try_lock(&self) -> bool {
+        self.state.lock().owner.acquire(TaskHandle::current())
+    }
+
+    // ネストを1段戻す。最後のunlockで開放し、待っているタスクを1つ起こす
+    pub fn raw_unlock(&self) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        match state.owner.release(TaskHandle::current()) {
+            Release::NotOwner => return Err(Error::NotOwner),
+            Release::Nested => {}
+            Release::Released => {
+                state.waiters.wake_one();
+            }
+        }
+        Ok(())
+    }
+
+    // ロックを保持しているタスク。Some(None)はカーネルが保持している
+    pub fn owner(&self) -> Option<Option<TaskHandle>> {
+        self.state.lock().owner.owner()
+    }
+}
+
+impl<T> Deref for RecursiveMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RecursiveMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // ガードはロックしたタスクから移動できないので、失敗しない
+        let _ = self.lock.raw_unlock();
+    }
+}
+
+unsafe impl<T: Send> Sync for RecursiveMutex<T> {}
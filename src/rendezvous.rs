@@ -0,0 +1,104 @@
+// ランデブー(同期チャネル)
+// バッファを持たず、送信側は受信側が値を受け取るまで、受信側は送信側が値を渡すまで待つ。
+// send()から戻ったときには、値は受信側に渡っている。
+//
+// static HANDOFF: Rendezvous<Frame> = Rendezvous::new();
+// HANDOFF.send(frame, None)?;         // 受け取られるまで待つ
+// let frame = HANDOFF.recv(Some(10))?; // 最大10tick待つ
+
+use crate::handoff::Handoff;
+use crate::mutex::Mutex;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError<T> {
+    Timeout(T), // 受け取られなかった値を返す
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    Timeout,
+}
+
+struct State<T> {
+    handoff: Handoff<T>,
+    senders: WaitList,
+    receivers: WaitList,
+}
+
+pub struct Rendezvous<T> {
+    state: Mutex<State<T>>,
+}
+
+impl<T> Rendezvous<T> {
+    pub const fn new() -> Self {
+        Rendezvous {
+            state: Mutex::new(State {
+                handoff: Handoff::new(),
+                senders: WaitList::new(),
+                receivers: WaitList::new(),
+            }),
+        }
+    }
+
+    // 受信側が受け取るまで待つ。timeoutはtick数、Noneなら無期限に待つ
+    pub fn send(&self, value: T, timeout: Option<u32>) -> Result<(), SendError<T>> {
+        let deadline = timeout.map(systick::deadline);
+        let mut value = Some(value);
+        let mut ticket = None;
+        loop {
+            {
+                let mut state = self.state.lock();
+                state.senders.remove_current();
+                match ticket {
+                    // 他の送信側が受け渡し中でなければslotに置く
+                    None => {
+                        ticket = state.handoff.put(&mut value);
+                        if ticket.is_some() {
+                            state.receivers.wake_one();
+                        }
+                    }
+                    Some(t) if state.handoff.delivered(t) => return Ok(()),
+                    Some(_) => {}
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    // slotに置いたままなら取り戻して、slotの空きを待っている送信側を起こす
+                    if value.is_none() {
+                        state.senders.wake_all();
+                    }
+                    return Err(SendError::Timeout(state.handoff.reclaim(&mut value)));
+                }
+                state.senders.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 送信側が値を渡すまで待つ
+    pub fn recv(&self, timeout: Option<u32>) -> Result<T, RecvError> {
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut state = self.state.lock();
+                state.receivers.remove_current();
+                if let Some(value) = state.handoff.take() {
+                    // 渡した送信側と、slotの空きを待っている送信側を起こす
+                    state.senders.wake_all();
+                    return Ok(value);
+                }
+                if deadline.is_some_and(systick::is_expired) {
+                    return Err(RecvError::Timeout);
+                }
+                state.receivers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+}
+
+impl<T> Default for Rendezvous<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
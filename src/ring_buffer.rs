@@ -0,0 +1,156 @@
+// ロックフリーのSPSC(単一プロデューサ・単一コンシューマ)リングバッファ
+// UARTやADCの割り込みハンドラ(プロデューサ)からタスク(コンシューマ)へ、ロックを取らずにデータを渡す。
+// 本体(spsc::Ring)はアトミックなload/storeだけで実装している。
+// コンシューマが待っているときだけ、起こすためにクリティカルセクションに入る(spsc::Waiter)。
+//
+// static RX: RingBuffer<u8, 64> = RingBuffer::new();
+// let (mut producer, mut consumer) = RX.split().unwrap();
+// producer.push(byte);               // 割り込みハンドラ
+// let byte = consumer.recv(None)?;   // タスク。データが届くまで待つ
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::critical_section;
+use crate::spsc::{Ring, Waiter};
+use crate::systick;
+use crate::task::{Task, TaskHandle};
+use crate::wait_list;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+}
+
+pub struct RingBuffer<T, const N: usize> {
+    ring: Ring<T, N>,
+    waiter: Waiter<Task<'static>>, // データを待っているコンシューマ
+    split: AtomicBool,
+}
+
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            ring: Ring::new(),
+            waiter: Waiter::new(),
+            split: AtomicBool::new(false),
+        }
+    }
+
+    // プロデューサとコンシューマを取り出す。1度しか取り出せない(2度目以降はNone)
+    pub fn split(&self) -> Option<(Producer<'_, T, N>, Consumer<'_, T, N>)> {
+        let already = critical_section::with(|_| {
+            let already = self.split.load(Ordering::Relaxed);
+            self.split.store(true, Ordering::Relaxed);
+            already
+        });
+        if already {
+            return None;
+        }
+        Some((Producer { ring: self }, Consumer { ring: self }))
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    // waiterを取り出して起こす。
+    // コンシューマがwaiterを消すのと同じクリティカルセクションの中で起こすので、
+    // 待ち終わって別のもの(IPCなど)を待っているタスクを起こしてしまうことはない
+    fn wake_waiter(&self) {
+        critical_section::with(|_| {
+            if let Some(task) = TaskHandle::from_ptr(self.waiter.take()) {
+                task.wake();
+            }
+        });
+    }
+
+    fn clear_waiter(&self) {
+        critical_section::with(|_| self.waiter.clear());
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    // 待たずに書き込む。いっぱいならvalueを返す。割り込みハンドラから呼べる
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        // Producerは1つしかない(split()は1度だけ)
+        unsafe { self.ring.ring.push(value) }?;
+        // コンシューマはwaiterをセットしてからtailを確認するので、どちらかが必ず相手の書き込みを見る
+        if self.ring.waiter.is_registered() {
+            self.ring.wake_waiter();
+        }
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.ring.len() == N
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    // 待たずに読み出す
+    pub fn pop(&mut self) -> Option<T> {
+        // Consumerは1つしかない(split()は1度だけ)
+        unsafe { self.ring.ring.pop() }
+    }
+
+    // データが届くまで待って読み出す。timeoutはtick数、Noneなら無期限に待つ
+    pub fn recv(&mut self, timeout: Option<u32>) -> Result<T, Error> {
+        let deadline = timeout.map(systick::deadline);
+        let me = TaskHandle::current();
+        loop {
+            if let Some(value) = self.pop() {
+                return Ok(value);
+            }
+            if deadline.is_some_and(systick::is_expired) {
+                return Err(Error::Timeout);
+            }
+            let Some(me) = me else {
+                // タスク以外から呼ばれた場合はポーリングする
+                core::hint::spin_loop();
+                continue;
+            };
+            // 待ち状態にしてwaiterを登録してから、もう一度確認する
+            me.block(deadline);
+            self.ring.waiter.register(me.as_ptr());
+            let value = self.pop();
+            if value.is_some() {
+                me.unblock();
+            } else {
+                wait_list::suspend();
+            }
+            // 起こされたか、タイムアウトしたか、待たなかった。どの場合もwaiterを消してから次に進む
+            self.ring.clear_waiter();
+            if let Some(value) = value {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
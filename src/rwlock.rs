@@ -1,19 +1,26 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{self, AtomicBool};
-use rp2040_hal::sio::Spinlock0;
 
-pub struct RwLockReadGuard<'a, T> {
-    lock: &'a RwLock<T>,
+use crate::lock_order::MAX_LEVEL;
+use crate::spinlock::{HeldLevel, LockLevel, Spinlock};
+
+// LEVELはロックの順序(spinlock::LockLevel参照)
+pub struct RwLockReadGuard<'a, T, const LEVEL: u8 = 0> {
+    lock: &'a RwLock<T, LEVEL>,
+    _level: HeldLevel,
 }
 
-impl<'a, T> RwLockReadGuard<'a, T> {
-    fn new(lock: &'a RwLock<T>) -> Self {
-        RwLockReadGuard { lock }
+impl<'a, T, const LEVEL: u8> RwLockReadGuard<'a, T, LEVEL> {
+    fn new(lock: &'a RwLock<T, LEVEL>, level: HeldLevel) -> Self {
+        RwLockReadGuard {
+            lock,
+            _level: level,
+        }
     }
 }
 
-impl<T> Deref for RwLockReadGuard<'_, T> {
+impl<T, const LEVEL: u8> Deref for RwLockReadGuard<'_, T, LEVEL> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
@@ -22,83 +29,117 @@ impl<T> Deref for RwLockReadGuard<'_, T> {
 
 // RwLockReadGuardはDerefMutを実装しない
 
-impl<T> Drop for RwLockReadGuard<'_, T> {
+impl<T, const LEVEL: u8> Drop for RwLockReadGuard<'_, T, LEVEL> {
     fn drop(&mut self) {
         self.lock.unlock();
     }
 }
 
-pub struct RwLockWriteGuard<'a, T> {
-    lock: &'a RwLock<T>,
+pub struct RwLockWriteGuard<'a, T, const LEVEL: u8 = 0> {
+    lock: &'a RwLock<T, LEVEL>,
+    _level: HeldLevel,
 }
 
-impl<'a, T> RwLockWriteGuard<'a, T> {
-    fn new(lock: &'a RwLock<T>) -> Self {
-        RwLockWriteGuard { lock }
+impl<'a, T, const LEVEL: u8> RwLockWriteGuard<'a, T, LEVEL> {
+    fn new(lock: &'a RwLock<T, LEVEL>, level: HeldLevel) -> Self {
+        RwLockWriteGuard {
+            lock,
+            _level: level,
+        }
     }
 }
 
-impl<T> Deref for RwLockWriteGuard<'_, T> {
+impl<T, const LEVEL: u8> Deref for RwLockWriteGuard<'_, T, LEVEL> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
     }
 }
 
-impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+impl<T, const LEVEL: u8> DerefMut for RwLockWriteGuard<'_, T, LEVEL> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
 }
 
-impl<T> Drop for RwLockWriteGuard<'_, T> {
+impl<T, const LEVEL: u8> Drop for RwLockWriteGuard<'_, T, LEVEL> {
     fn drop(&mut self) {
         self.lock.unlock();
     }
 }
 
-pub struct RwLock<T> {
+impl<T, const LEVEL: u8> LockLevel for RwLockReadGuard<'_, T, LEVEL> {
+    const LEVEL: u8 = LEVEL;
+}
+
+impl<T, const LEVEL: u8> LockLevel for RwLockWriteGuard<'_, T, LEVEL> {
+    const LEVEL: u8 = LEVEL;
+}
+
+pub struct RwLock<T, const LEVEL: u8 = 0> {
     locked: AtomicBool,
+    spinlock: Spinlock,
     data: UnsafeCell<T>,
 }
 
-impl<T> RwLock<T> {
+impl<T, const LEVEL: u8> RwLock<T, LEVEL> {
     pub const fn new(value: T) -> Self {
+        const { assert!(LEVEL <= MAX_LEVEL, "lock level is too high") };
         Self {
             locked: AtomicBool::new(false),
+            spinlock: Spinlock::new(),
             data: UnsafeCell::new(value),
         }
     }
     // 読み書きロック: 本当にロックする
-    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
-        // Aquire -> Releaseの順序が保証されるようにバリア命令が出力される
-        // バリア命令はCortex-M0+でも有る
-        while self.locked.load(atomic::Ordering::Acquire) {
-            // 他のスレッドがlockedを開放するまで待つ
-            core::hint::spin_loop();
+    pub fn write(&self) -> RwLockWriteGuard<'_, T, LEVEL> {
+        // 待つ前にロックの順序を確認する
+        let level = HeldLevel::enter(LEVEL, true);
+        loop {
+            {
+                // lockedの確認とセットを、このロックに割り当てたハードウェアスピンロックで保護する
+                let _lock = self.spinlock.claim();
+                if !self.locked.load(atomic::Ordering::Acquire) {
+                    self.locked.store(true, atomic::Ordering::Release);
+                    break;
+                }
+                // _lockがここでドロップされ、スピンロックがreleaseされる
+            }
+            // Aquire -> Releaseの順序が保証されるようにバリア命令が出力される
+            // バリア命令はCortex-M0+でも有る
+            while self.locked.load(atomic::Ordering::Acquire) {
+                // 他のスレッドがlockedを開放するまで待つ
+                core::hint::spin_loop();
+            }
         }
-        // self.lockedの操作をSpinLock0で保護する
-        let _lock = Spinlock0::claim();
-        self.locked.store(true, atomic::Ordering::Release);
-        RwLockWriteGuard::new(self)
-        // _lockがここでドロップされ、SpinLock0がreleaseされる
+        RwLockWriteGuard::new(self, level)
     }
     // 読み出しロック
-    pub fn read(&self) -> RwLockReadGuard<'_, T> {
-        // self.lockedの操作をSpinLock0で保護する
-        let _lock = Spinlock0::claim();
-        RwLockReadGuard::new(self)
-        // _lockがここでドロップされ、SpinLock0がreleaseされる
+    pub fn read(&self) -> RwLockReadGuard<'_, T, LEVEL> {
+        let level = HeldLevel::enter(LEVEL, true);
+        // self.lockedの操作を、このロックに割り当てたハードウェアスピンロックで保護する
+        let _lock = self.spinlock.claim();
+        RwLockReadGuard::new(self, level)
+        // _lockがここでドロップされ、スピンロックがreleaseされる
+    }
+    // heldを保持したままロックする。heldのレベルがこのロックより低くなければコンパイルエラー
+    pub fn write_after<G: LockLevel>(&self, _held: &G) -> RwLockWriteGuard<'_, T, LEVEL> {
+        const { assert!(G::LEVEL < LEVEL, "lock order violation") };
+        self.write()
+    }
+    pub fn read_after<G: LockLevel>(&self, _held: &G) -> RwLockReadGuard<'_, T, LEVEL> {
+        const { assert!(G::LEVEL < LEVEL, "lock order violation") };
+        self.read()
     }
     fn unlock(&self) {
         if !self.locked.load(atomic::Ordering::Acquire) {
             return;
         }
-        let _lock = Spinlock0::claim();
+        let _lock = self.spinlock.claim();
         self.locked.store(false, atomic::Ordering::Release);
     }
 }
 
-unsafe impl<T> Sync for RwLock<T> {}
-unsafe impl<T> Sync for RwLockReadGuard<'_, T> where T: Sync {}
-unsafe impl<T> Sync for RwLockWriteGuard<'_, T> where T: Sync {}
+unsafe impl<T, const LEVEL: u8> Sync for RwLock<T, LEVEL> {}
+unsafe impl<T, const LEVEL: u8> Sync for RwLockReadGuard<'_, T, LEVEL> where T: Sync {}
+unsafe impl<T, const LEVEL: u8> Sync for RwLockWriteGuard<'_, T, LEVEL> where T: Sync {}
@@ -1,22 +1,32 @@
 use core::cell::UnsafeCell;
 
+use crate::idle;
 use crate::linked_list::{LinkedList, ListItem};
 use crate::mutex::Mutex;
-use crate::task::Task;
+use crate::systick;
+use crate::task::{self, Task, TaskHandle};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct Scheduler<'a> {
     ready: Mutex<UnsafeCell<LinkedList<'a, Task<'a>>>>,
+    len: AtomicUsize,
 }
 
 impl<'a> Scheduler<'a> {
     pub const fn new() -> Self {
         Scheduler {
             ready: Mutex::new(UnsafeCell::new(LinkedList::new())),
+            len: AtomicUsize::new(0),
         }
     }
 
-    pub fn push_back(&self, item: &'a mut ListItem<'a, Task<'a>>) {
+    // 追加したタスクのハンドルを返す(通知の宛先などに使う)
+    pub fn push_back(&self, item: &'a mut ListItem<'a, Task<'a>>) -> TaskHandle {
+        let handle = TaskHandle::from_task(item);
         unsafe { self.ready.lock().get().as_mut().unwrap().push_back(item) };
+        self.len
+            .store(self.len.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        handle
     }
 
     fn schedule_next(&self) {
@@ -24,16 +34,27 @@ impl<'a> Scheduler<'a> {
     }
 
     pub fn exec(&self) -> ! {
+        let mut idle_task = idle::task();
+        // 続けて実行できなかったタスクの数。
+        // 全てのタスクが待っていたら(タスクがなくても)、アイドルタスクを実行して次の期限まで眠る
+        let mut idle = 0;
         loop {
+            if idle == 0 {
+                task::clear_wake_pending();
+            }
             let current = unsafe { self.ready.lock().get().as_mut().unwrap().front_mut() };
-            match current {
-                None => {
-                    unimplemented!();
-                }
-                Some(p) => {
-                    p.exec();
+            if let Some(p) = current {
+                if p.exec() {
+                    idle = 0;
+                } else {
+                    idle += 1;
                 }
             }
+            if idle >= self.len.load(Ordering::Relaxed) {
+                idle_task.exec();
+                systick::idle();
+                idle = 0;
+            }
             self.schedule_next();
         }
     }
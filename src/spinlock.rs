@@ -0,0 +1,164 @@
+// RP2040のハードウェアスピンロック(SIO SPINLOCK0-31)の割り当て
+// ロックごとに別のスピンロックを割り当てて、無関係なロック同士が競合しないようにする。
+// スピンロック31はcritical_sectionが使うので、0-30を順に割り当てる。
+// 使い切ったら先頭から共有する(正しさは変わらず、競合が増えるだけ)。
+
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use cortex_m::peripheral::scb::VectActive;
+use cortex_m::peripheral::SCB;
+use rp2040_hal::pac;
+use rp2040_hal::Sio;
+
+use crate::critical_section;
+use crate::lock_order::HeldLevels;
+use crate::task::TaskHandle;
+
+pub const NUM_SPINLOCKS: u8 = 31;
+const UNALLOCATED: u8 = u8::MAX;
+
+// 次に割り当てるスピンロック番号。critical_sectionの中で更新する
+static NEXT_SPINLOCK: AtomicU8 = AtomicU8::new(0);
+
+fn allocate() -> u8 {
+    let id = NEXT_SPINLOCK.load(Ordering::Relaxed);
+    NEXT_SPINLOCK.store(id.wrapping_add(1), Ordering::Relaxed);
+    id % NUM_SPINLOCKS
+}
+
+// ロックインスタンスが持つハードウェアスピンロック
+// staticに置けるように、実際の割り当ては最初にclaimしたときに行う
+pub struct Spinlock {
+    id: AtomicU8,
+}
+
+pub struct SpinlockGuard {
+    id: u8,
+}
+
+impl Spinlock {
+    pub const fn new() -> Self {
+        Spinlock {
+            id: AtomicU8::new(UNALLOCATED),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        let id = self.id.load(Ordering::Acquire);
+        if id != UNALLOCATED {
+            return id;
+        }
+        critical_section::with(|_| {
+            // 他のタスクやコアが先に割り当てたかもしれないので、もう一度確認する
+            let id = self.id.load(Ordering::Acquire);
+            if id != UNALLOCATED {
+                return id;
+            }
+            let id = allocate();
+            self.id.store(id, Ordering::Release);
+            id
+        })
+    }
+
+    pub fn try_claim(&self) -> Option<SpinlockGuard> {
+        let id = self.id();
+        // 読み出すとロックを試みる。0以外なら取れた
+        let sio = unsafe { &*pac::SIO::ptr() };
+        if sio.spinlock(id as usize).read().bits() != 0 {
+            Some(SpinlockGuard { id })
+        } else {
+            None
+        }
+    }
+
+    pub fn claim(&self) -> SpinlockGuard {
+        loop {
+            if let Some(guard) = self.try_claim() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Default for Spinlock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for SpinlockGuard {
+    fn drop(&mut self) {
+        // 書き込むと開放する
+        unsafe {
+            let sio = &*pac::SIO::ptr();
+            sio.spinlock(self.id as usize)
+                .write_with_zero(|b| b.bits(1));
+        }
+    }
+}
+
+// ロックの順序(レベル)。
+// 複数のロックをネストして取るときは、レベルの低いロックから順に lock_after() で取る。
+// 順序が逆だとコンパイルエラーになるので、ロック順序の逆転によるデッドロックを防げる。
+// lock()で取った場合も、保持しているロックのレベルを記録して、デバッグビルドでは実行時に確認する。
+// レベル0(既定)のロックは順序を決めないので確認しない。レベルは最大でlock_order::MAX_LEVEL。
+//
+// static BUS: Mutex<Bus, 1> = Mutex::new(Bus::new());
+// static DEVICE: Mutex<Device, 2> = Mutex::new(Device::new());
+// let bus = BUS.lock();
+// let device = DEVICE.lock_after(&bus); // BUS(1) -> DEVICE(2) の順ならOK
+pub trait LockLevel {
+    const LEVEL: u8;
+}
+
+// タスク以外(カーネル、割り込みハンドラ)が保持しているロックのレベル(lock_order::HeldLevels)。コアごと
+static CORE_LEVELS: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+// 保持しているロックのレベルの記録。タスクはタスクごと、それ以外はコアごと
+// 書き換えるのは保持しているタスクかコア自身だけ。割り込みハンドラは戻るまでに自分のロックを開放するので、
+// 読んでから書き込むまでの間に割り込まれても記録は変わらない
+fn held_levels() -> &'static AtomicU32 {
+    match TaskHandle::current() {
+        Some(task) if SCB::vect_active() == VectActive::ThreadMode => task.lock_levels(),
+        _ => &CORE_LEVELS[Sio::core() as usize],
+    }
+}
+
+// ガードが持つ記録。dropすると、このロックのレベルを記録から消す
+// 取った順の逆に開放しなくても、他のロックの記録は残る
+pub(crate) struct HeldLevel {
+    level: u8, // 記録したレベル。0なら何も記録していない
+}
+
+impl HeldLevel {
+    // try_lock()は待たないのでデッドロックしない。checkをfalseにして記録だけする
+    // try_lock()で同じレベルを重ねて取った場合は、先に取ったほうだけが記録を持つ
+    pub(crate) fn enter(level: u8, check: bool) -> Self {
+        if level == 0 {
+            return HeldLevel { level };
+        }
+        let held = held_levels();
+        let levels = HeldLevels::from_bits(held.load(Ordering::Relaxed));
+        debug_assert!(
+            !check || levels.allows(level),
+            "lock order violation: level {} while holding level {}",
+            level,
+            levels.highest()
+        );
+        if levels.contains(level) {
+            return HeldLevel { level: 0 };
+        }
+        held.store(levels.with(level).bits(), Ordering::Relaxed);
+        HeldLevel { level }
+    }
+}
+
+impl Drop for HeldLevel {
+    fn drop(&mut self) {
+        if self.level != 0 {
+            let held = held_levels();
+            let levels = HeldLevels::from_bits(held.load(Ordering::Relaxed));
+            held.store(levels.without(self.level).bits(), Ordering::Relaxed);
+        }
+    }
+}
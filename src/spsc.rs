@@ -0,0 +1,564 @@
+// SPSC(単一プロデューサ・単一コンシューマ)リングバッファの本体と、コンシューマを起こす手順。RingBufferの中身
+// Cortex-M0+(ARMv6-M)にはCAS命令がないので、アトミックなload/storeだけで実装する。
+// headはコンシューマだけが、tailはプロデューサだけが書き込むので、CASは不要。
+// タスクやクリティカルセクションには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/spsc.rs
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// head, tailは 0..2N の範囲を回る。
+// 0..Nにすると、満杯(tail - head == N)と空(tail == head)が区別できない。
+fn next<const N: usize>(index: usize) -> usize {
+    (index + 1) % (2 * N)
+}
+
+fn len<const N: usize>(head: usize, tail: usize) -> usize {
+    (tail + 2 * N - head) % (2 * N)
+}
+
+fn slot<const N: usize>(index: usize) -> usize {
+    index % N
+}
+
+pub struct Ring<T, const N: usize> {
+    buf: [UnsafeCell<MaybeUninit<T>>; N],
+    head: AtomicUsize, // 次に読む位置。コンシューマだけが書き込む
+    tail: AtomicUsize, // 次に書く位置。プロデューサだけが書き込む
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "RingBuffer needs at least one slot") };
+        Ring {
+            buf: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        len::<N>(
+            self.head.load(Ordering::Acquire),
+            self.tail.load(Ordering::Acquire),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// # Safety
+    /// push()を同時に呼べるのは1つのプロデューサだけ
+    pub unsafe fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if len::<N>(head, tail) == N {
+            return Err(value);
+        }
+        (*self.buf[slot::<N>(tail)].get()).write(value);
+        // 書き込んだデータはtailを進めたあとでコンシューマから見える
+        // このあとwaiterを確認するので、SeqCstにする(Waiter参照)
+        self.tail.store(next::<N>(tail), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// # Safety
+    /// pop()を同時に呼べるのは1つのコンシューマだけ
+    pub unsafe fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::SeqCst);
+        if head == tail {
+            return None;
+        }
+        let value = (*self.buf[slot::<N>(head)].get()).assume_init_read();
+        // headを進めたあとでプロデューサがスロットを再利用する
+        self.head.store(next::<N>(head), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<T, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe { self.buf[slot::<N>(head)].get_mut().assume_init_drop() };
+            head = next::<N>(head);
+        }
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+
+// データを待っているコンシューマ(RingBufferではタスク)
+// コンシューマ: 待ち状態にする → register() → もう一度pop() → 待つ → ロックの中でclear()
+// プロデューサ: push() → is_registered()ならロックの中でtake()して、同じロックの中で起こす
+// コンシューマは登録してからtailを、プロデューサはtailを進めてから登録を確認する(どちらもSeqCst)ので、
+// どちらかが必ず相手の書き込みを見る。
+// take()と起こすのを、clear()と同じロックの中で行うので、待ち終わって別のもの(IPCなど)を
+// 待っているコンシューマを起こしてしまうことはない
+pub struct Waiter<T> {
+    task: AtomicPtr<T>,
+}
+
+impl<T> Waiter<T> {
+    pub const fn new() -> Self {
+        Waiter {
+            task: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn register(&self, task: *mut T) {
+        self.task.store(task, Ordering::SeqCst);
+    }
+
+    pub fn is_registered(&self) -> bool {
+        !self.task.load(Ordering::SeqCst).is_null()
+    }
+
+    // ロックの中で呼ぶ
+    pub fn take(&self) -> *mut T {
+        let task = self.task.load(Ordering::Relaxed);
+        self.task.store(ptr::null_mut(), Ordering::Relaxed);
+        task
+    }
+
+    // ロックの中で呼ぶ
+    pub fn clear(&self) {
+        self.task.store(ptr::null_mut(), Ordering::Relaxed);
+    }
+}
+
+impl<T> Default for Waiter<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::{len, next, slot, Ring};
+    use std::collections::HashSet;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_index() {
+        // N = 3: 0..6 を回る
+        assert_eq!(1, next::<3>(0));
+        assert_eq!(0, next::<3>(5));
+        assert_eq!(0, len::<3>(4, 4));
+        assert_eq!(3, len::<3>(4, 1));
+        assert_eq!(2, len::<3>(5, 1));
+        assert_eq!(1, slot::<3>(4));
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let ring: Ring<u32, 3> = Ring::new();
+        unsafe {
+            assert_eq!(None, ring.pop());
+            for round in 0..5 {
+                assert_eq!(Ok(()), ring.push(round));
+                assert_eq!(Ok(()), ring.push(round + 10));
+                assert_eq!(Ok(()), ring.push(round + 20));
+                assert_eq!(3, ring.len());
+                assert_eq!(Err(99), ring.push(99));
+                assert_eq!(Some(round), ring.pop());
+                assert_eq!(Some(round + 10), ring.pop());
+                assert_eq!(Some(round + 20), ring.pop());
+                assert_eq!(None, ring.pop());
+            }
+        }
+    }
+
+    // 状態を深さ優先で全て訪れる。stepは次の状態を返す。全員が終わった状態に着けばtrue
+    // 自分自身にしか進めない(スピンしているだけの)状態で止まったらErr
+    fn explore<S: Copy + Eq + std::hash::Hash>(
+        start: S,
+        done: impl Fn(&S) -> bool,
+        step: impl Fn(&S) -> Result<Vec<S>, &'static str>,
+    ) -> Result<bool, &'static str> {
+        let mut visited = HashSet::new();
+        let mut stack = std::vec![start];
+        let mut finished = false;
+        while let Some(state) = stack.pop() {
+            if !visited.insert(state) {
+                continue;
+            }
+            if done(&state) {
+                finished = true;
+                continue;
+            }
+            let next: Vec<S> = step(&state)?.into_iter().filter(|s| *s != state).collect();
+            if next.is_empty() {
+                return Err("stuck");
+            }
+            stack.extend(next);
+        }
+        Ok(finished)
+    }
+
+    // 割り込みハンドラとタスクのあらゆる実行順序(インターリーブ)を網羅的に試す。
+    // push/popをアトミック操作の単位に分解し、共有状態(head, tail, スロット)と
+    // 各スレッドのローカル状態の全ての組み合わせを探索する。
+    // 1度読んだスロットを再び読む、読んでいないスロットを上書きする、
+    // 順番が入れ替わる、のいずれかが起きたら失敗。
+    const N: usize = 2;
+    const COUNT: u8 = 6; // プロデューサが書き込む値の数(1..=COUNT)。インデックスが一周以上するように
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct Shared {
+        head: usize,
+        tail: usize,
+        slots: [Option<u8>; N], // None = 空き(読み出し済み)
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum ProducerStep {
+        LoadHead { value: u8 },               // headを読む
+        Write { value: u8, tail: usize },     // スロットに書く
+        StoreTail { value: u8, tail: usize }, // tailを進める
+        Done,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum ConsumerStep {
+        LoadTail { expect: u8 },
+        Read { expect: u8, head: usize },
+        StoreHead { expect: u8, head: usize },
+        Done,
+    }
+
+    fn step_producer(shared: &mut Shared, p: ProducerStep) -> ProducerStep {
+        match p {
+            ProducerStep::LoadHead { value } => {
+                let tail = shared.tail;
+                if len::<N>(shared.head, tail) == N {
+                    // いっぱい: やり直す
+                    ProducerStep::LoadHead { value }
+                } else {
+                    ProducerStep::Write { value, tail }
+                }
+            }
+            ProducerStep::Write { value, tail } => {
+                let s = &mut shared.slots[slot::<N>(tail)];
+                assert!(s.is_none(), "overwrote an unread slot");
+                *s = Some(value);
+                ProducerStep::StoreTail { value, tail }
+            }
+            ProducerStep::StoreTail { value, tail } => {
+                shared.tail = next::<N>(tail);
+                if value == COUNT {
+                    ProducerStep::Done
+                } else {
+                    ProducerStep::LoadHead { value: value + 1 }
+                }
+            }
+            ProducerStep::Done => ProducerStep::Done,
+        }
+    }
+
+    fn step_consumer(shared: &mut Shared, c: ConsumerStep) -> ConsumerStep {
+        match c {
+            ConsumerStep::LoadTail { expect } => {
+                let head = shared.head;
+                if head == shared.tail {
+                    // 空: やり直す
+                    ConsumerStep::LoadTail { expect }
+                } else {
+                    ConsumerStep::Read { expect, head }
+                }
+            }
+            ConsumerStep::Read { expect, head } => {
+                let value = shared.slots[slot::<N>(head)].take();
+                assert_eq!(
+                    Some(expect),
+                    value,
+                    "read an unwritten slot or out of order"
+                );
+                ConsumerStep::StoreHead { expect, head }
+            }
+            ConsumerStep::StoreHead { expect, head } => {
+                shared.head = next::<N>(head);
+                if expect == COUNT {
+                    ConsumerStep::Done
+                } else {
+                    ConsumerStep::LoadTail { expect: expect + 1 }
+                }
+            }
+            ConsumerStep::Done => ConsumerStep::Done,
+        }
+    }
+
+    #[test]
+    fn test_all_interleavings() {
+        type State = (Shared, ProducerStep, ConsumerStep);
+        let start: State = (
+            Shared {
+                head: 0,
+                tail: 0,
+                slots: [None; N],
+            },
+            ProducerStep::LoadHead { value: 1 },
+            ConsumerStep::LoadTail { expect: 1 },
+        );
+        let finished = explore(
+            start,
+            |&(shared, p, c)| {
+                let done = p == ProducerStep::Done && c == ConsumerStep::Done;
+                assert!(!done || shared.head == shared.tail);
+                done
+            },
+            |&(shared, p, c)| {
+                let mut next = Vec::new();
+                if p != ProducerStep::Done {
+                    let mut s = shared;
+                    let p = step_producer(&mut s, p);
+                    next.push((s, p, c));
+                }
+                if c != ConsumerStep::Done {
+                    let mut s = shared;
+                    let c = step_consumer(&mut s, c);
+                    next.push((s, p, c));
+                }
+                Ok(next)
+            },
+        );
+        assert_eq!(Ok(true), finished);
+    }
+
+    // コンシューマを待たせて起こす手順(Waiter)のモデル。
+    // push/popは(上で確かめたので)1ステップとし、waiterの操作とタスクの待ち状態を分解する。
+    // 起床が失われて、データがあるのにコンシューマが眠ったままになったら"stuck"、
+    // コンシューマがリングバッファ以外(IPCなど)を待っている間に起こしたら"stray wake"。
+    // locked_wake: プロデューサがwaiterを取り出して起こすのを、clear()と同じロックの中で行う
+    const ITEMS: u8 = 3;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum Blocked {
+        No,
+        Ring,  // リングバッファのデータを待っている
+        Other, // 受信し終わって、別のものを待っている
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct Task {
+        blocked: Blocked,
+        woken: bool,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct World {
+        len: u8,      // リングバッファにあるデータの数
+        waiter: bool, // コンシューマが登録されている
+        task: Task,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum Produce {
+        Push(u8),      // push()してtailを進める
+        Check(u8),     // is_registered()
+        Wake(u8),      // ロックの中でtake()して起こす(locked_wake)
+        Take(u8),      // ロックなしでtake()する
+        WakeTaken(u8), // 取り出したタスクをあとで起こす
+        Done,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    enum Consume {
+        Pop(u8),         // 待つ前にpop()
+        Block(u8),       // 待ち状態にする
+        Register(u8),    // register()
+        Recheck(u8),     // もう一度pop()。あればunblock()
+        Suspend(u8),     // 起こされるまで実行されない
+        Clear(u8, bool), // ロックの中でclear()。trueなら受信した
+        Other(u8),       // 別のものを待つ
+        OtherDone(u8),   // 別のものが届いた
+        Done,
+    }
+
+    fn wake(world: &mut World) -> Result<(), &'static str> {
+        if world.task.blocked == Blocked::Other {
+            return Err("stray wake");
+        }
+        world.task.woken = true;
+        Ok(())
+    }
+
+    fn produce(w: &mut World, p: Produce, locked_wake: bool) -> Result<Produce, &'static str> {
+        let next = |i: u8| {
+            if i == ITEMS {
+                Produce::Done
+            } else {
+                Produce::Push(i + 1)
+            }
+        };
+        Ok(match p {
+            Produce::Push(i) => {
+                if w.len as usize == N {
+                    Produce::Push(i)
+                } else {
+                    w.len += 1;
+                    Produce::Check(i)
+                }
+            }
+            Produce::Check(i) if w.waiter => {
+                if locked_wake {
+                    Produce::Wake(i)
+                } else {
+                    Produce::Take(i)
+                }
+            }
+            Produce::Check(i) => next(i),
+            Produce::Wake(i) => {
+                if w.waiter {
+                    w.waiter = false;
+                    wake(w)?;
+                }
+                next(i)
+            }
+            Produce::Take(i) => {
+                let taken = w.waiter;
+                w.waiter = false;
+                if taken {
+                    Produce::WakeTaken(i)
+                } else {
+                    next(i)
+                }
+            }
+            Produce::WakeTaken(i) => {
+                wake(w)?;
+                next(i)
+            }
+            Produce::Done => Produce::Done,
+        })
+    }
+
+    fn consume(w: &mut World, c: Consume) -> Consume {
+        let pop = |w: &mut World| {
+            let some = w.len > 0;
+            if some {
+                w.len -= 1;
+            }
+            some
+        };
+        match c {
+            Consume::Pop(i) => {
+                if pop(w) {
+                    Consume::Other(i)
+                } else {
+                    Consume::Block(i)
+                }
+            }
+            Consume::Block(i) => {
+                w.task = Task {
+                    blocked: Blocked::Ring,
+                    woken: false,
+                };
+                Consume::Register(i)
+            }
+            Consume::Register(i) => {
+                w.waiter = true;
+                Consume::Recheck(i)
+            }
+            Consume::Recheck(i) => {
+                if pop(w) {
+                    w.task.blocked = Blocked::No;
+                    Consume::Clear(i, true)
+                } else {
+                    Consume::Suspend(i)
+                }
+            }
+            Consume::Suspend(i) => {
+                if !w.task.woken {
+                    // スケジューラは起こされていないタスクを実行しない
+                    return Consume::Suspend(i);
+                }
+                w.task = Task {
+                    blocked: Blocked::No,
+                    woken: false,
+                };
+                Consume::Clear(i, false)
+            }
+            Consume::Clear(i, received) => {
+                w.waiter = false;
+                if received {
+                    Consume::Other(i)
+                } else {
+                    Consume::Pop(i)
+                }
+            }
+            Consume::Other(i) => {
+                w.task = Task {
+                    blocked: Blocked::Other,
+                    woken: false,
+                };
+                Consume::OtherDone(i)
+            }
+            Consume::OtherDone(i) => {
+                w.task.blocked = Blocked::No;
+                if i == ITEMS {
+                    Consume::Done
+                } else {
+                    Consume::Pop(i + 1)
+                }
+            }
+            Consume::Done => Consume::Done,
+        }
+    }
+
+    fn check_waiter(locked_wake: bool) -> Result<bool, &'static str> {
+        let start = (
+            World {
+                len: 0,
+                waiter: false,
+                task: Task {
+                    blocked: Blocked::No,
+                    woken: false,
+                },
+            },
+            Produce::Push(1),
+            Consume::Pop(1),
+        );
+        explore(
+            start,
+            |&(w, p, c)| {
+                let done = p == Produce::Done && c == Consume::Done;
+                assert!(!done || (w.len == 0 && !w.waiter));
+                done
+            },
+            |&(w, p, c)| {
+                let mut next = Vec::new();
+                if p != Produce::Done {
+                    let mut w = w;
+                    let p = produce(&mut w, p, locked_wake)?;
+                    next.push((w, p, c));
+                }
+                if c != Consume::Done {
+                    let mut w = w;
+                    let c = consume(&mut w, c);
+                    next.push((w, p, c));
+                }
+                Ok(next)
+            },
+        )
+    }
+
+    #[test]
+    fn test_waiter_handshake() {
+        assert_eq!(Ok(true), check_waiter(true));
+        // ロックの外で取り出して起こすと、別のものを待っているコンシューマを起こしてしまう
+        assert_eq!(Err("stray wake"), check_waiter(false));
+    }
+}
@@ -0,0 +1,227 @@
+// バイトストリームバッファ
+// 長さの決まっていないバイト列を、タスク間や割り込みハンドラからタスクへ渡す。
+// 読み出し側は、トリガーレベル以上のバイトが溜まるまで(またはタイムアウトまで)待つ。
+// 1バイトごとに読み出し側を起こさずにすむ。
+//
+// static RX: StreamBuffer<128> = StreamBuffer::new(16);
+// RX.write_from_isr(&bytes);                 // 割り込みハンドラ
+// let n = RX.read(&mut buf, Some(10));       // 16バイト溜まるか10tick経つまで待つ
+
+use crate::critical_section::IrqMutex;
+use crate::systick;
+use crate::wait_list::{self, WaitList};
+
+// バイトのリングバッファ本体。MessageBufferも使う
+pub(crate) struct ByteRing<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub(crate) const fn new() -> Self {
+        const { assert!(N > 0, "ByteRing needs at least one byte") };
+        ByteRing {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn free(&self) -> usize {
+        N - self.len
+    }
+
+    // 書き込めるだけ書き込んで、書き込んだバイト数を返す
+    pub(crate) fn write(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        let start = (self.head + self.len) % N;
+        let first = n.min(N - start);
+        self.buf[start..start + first].copy_from_slice(&data[..first]);
+        self.buf[..n - first].copy_from_slice(&data[first..n]);
+        self.len += n;
+        n
+    }
+
+    // 消費せずにコピーする
+    pub(crate) fn peek(&self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        let first = n.min(N - self.head);
+        out[..first].copy_from_slice(&self.buf[self.head..self.head + first]);
+        out[first..n].copy_from_slice(&self.buf[..n - first]);
+        n
+    }
+
+    pub(crate) fn discard(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.head = (self.head + n) % N;
+        self.len -= n;
+    }
+
+    pub(crate) fn read(&mut self, out: &mut [u8]) -> usize {
+        let n = self.peek(out);
+        self.discard(n);
+        n
+    }
+}
+
+struct Inner<const N: usize> {
+    ring: ByteRing<N>,
+    trigger_level: usize,
+    readers: WaitList,
+    writers: WaitList,
+}
+
+impl<const N: usize> Inner<N> {
+    fn write(&mut self, data: &[u8]) -> usize {
+        let n = self.ring.write(data);
+        if self.ring.len() >= self.trigger_level {
+            self.readers.wake_all();
+        }
+        n
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = self.ring.read(buf);
+        if n > 0 {
+            self.writers.wake_all();
+        }
+        n
+    }
+}
+
+pub struct StreamBuffer<const N: usize> {
+    inner: IrqMutex<Inner<N>>,
+}
+
+impl<const N: usize> StreamBuffer<N> {
+    // trigger_levelは1..=Nに丸める
+    pub const fn new(trigger_level: usize) -> Self {
+        StreamBuffer {
+            inner: IrqMutex::new(Inner {
+                ring: ByteRing::new(),
+                trigger_level: clamp_trigger_level::<N>(trigger_level),
+                readers: WaitList::new(),
+                writers: WaitList::new(),
+            }),
+        }
+    }
+
+    pub fn set_trigger_level(&self, trigger_level: usize) {
+        self.inner.lock().trigger_level = clamp_trigger_level::<N>(trigger_level);
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // 全て書き込むまで待つ。timeoutはtick数、Noneなら無期限に待つ
+    // 書き込んだバイト数を返す(タイムアウトした場合はdata.len()より少ない)
+    pub fn write(&self, data: &[u8], timeout: Option<u32>) -> usize {
+        let deadline = timeout.map(systick::deadline);
+        let mut written = 0;
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.writers.remove_current();
+                written += inner.write(&data[written..]);
+                if written == data.len() || deadline.is_some_and(systick::is_expired) {
+                    return written;
+                }
+                inner.writers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // トリガーレベル以上のバイトが溜まるまで待って、bufに読み出す
+    // タイムアウトした場合は、その時点で溜まっている分を読み出す(0のこともある)
+    pub fn read(&self, buf: &mut [u8], timeout: Option<u32>) -> usize {
+        let deadline = timeout.map(systick::deadline);
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                inner.readers.remove_current();
+                if inner.ring.len() >= inner.trigger_level
+                    || deadline.is_some_and(systick::is_expired)
+                {
+                    return inner.read(buf);
+                }
+                inner.readers.block_current(deadline);
+            }
+            wait_list::suspend();
+        }
+    }
+
+    // 待たずに書き込めるだけ書き込む。割り込みハンドラから呼べる
+    pub fn write_from_isr(&self, data: &[u8]) -> usize {
+        self.inner.lock().write(data)
+    }
+
+    // 待たずに溜まっている分を読み出す。割り込みハンドラから呼べる
+    pub fn read_from_isr(&self, buf: &mut [u8]) -> usize {
+        self.inner.lock().read(buf)
+    }
+}
+
+const fn clamp_trigger_level<const N: usize>(trigger_level: usize) -> usize {
+    if trigger_level == 0 {
+        1
+    } else if trigger_level > N {
+        N
+    } else {
+        trigger_level
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ByteRing;
+
+    #[test]
+    fn test_byte_ring() {
+        let mut ring: ByteRing<8> = ByteRing::new();
+        let mut out = [0u8; 8];
+        assert_eq!(0, ring.read(&mut out));
+
+        assert_eq!(5, ring.write(&[1, 2, 3, 4, 5]));
+        assert_eq!(3, ring.free());
+        assert_eq!(3, ring.write(&[6, 7, 8, 9, 10]));
+        assert_eq!(0, ring.free());
+
+        assert_eq!(3, ring.read(&mut out[..3]));
+        assert_eq!([1, 2, 3], out[..3]);
+
+        // 末尾から先頭に折り返して書き込む
+        assert_eq!(3, ring.write(&[11, 12, 13]));
+        assert_eq!(8, ring.read(&mut out));
+        assert_eq!([4, 5, 6, 7, 8, 11, 12, 13], out);
+        assert_eq!(0, ring.len());
+    }
+
+    #[test]
+    fn test_byte_ring_peek() {
+        let mut ring: ByteRing<4> = ByteRing::new();
+        let mut out = [0u8; 4];
+        ring.write(&[1, 2, 3]);
+        ring.discard(2);
+        ring.write(&[4, 5, 6]);
+        assert_eq!(4, ring.peek(&mut out));
+        assert_eq!([3, 4, 5, 6], out);
+        assert_eq!(4, ring.len());
+        ring.discard(10);
+        assert_eq!(0, ring.len());
+    }
+}
@@ -1,4 +1,9 @@
 use core::arch::asm;
+use cortex_m::peripheral::SCB;
+use cortex_m::register::control;
+use cortex_m_rt::ExceptionFrame;
+
+use crate::ipc;
 
 // システムコール
 // r0にシステムコール番号をセットして、svcを呼ぶ。
@@ -7,9 +12,56 @@ use core::arch::asm;
 // 戻り値はr0
 
 const SYSCALL_YEILD: u32 = 0;
+const SYSCALL_ENTER_PRIVILEGED: u32 = 1;
+pub(crate) const SYSCALL_IPC_SEND: u32 = 2;
+pub(crate) const SYSCALL_IPC_RECEIVE: u32 = 3;
+pub(crate) const SYSCALL_IPC_CALL: u32 = 4;
+pub(crate) const SYSCALL_IPC_REPLY: u32 = 5;
 
 pub fn back_to_kernel() {
     unsafe {
         asm!("svc 0", in("r0") SYSCALL_YEILD);
     }
 }
+
+// 呼び出したタスクを特権モードにして、割り込みを禁止する。critical_section::acquire()が使う
+// 非特権モードに戻すのはcritical_section::release()
+// どのタスクからも呼べるので、特権モードを制限する仕組みではない(critical_section.rsを参照)
+pub(crate) fn enter_privileged() {
+    unsafe {
+        asm!("svc 0", in("r0") SYSCALL_ENTER_PRIVILEGED);
+    }
+}
+
+// IPC。r1に引数(ipc::Request)のアドレスを渡す。
+// 呼び出したタスクが待ち状態になった場合、戻り値は相手がフレームのr0に書き込む
+pub(crate) fn ipc(number: u32, request: *mut ipc::Request) -> u32 {
+    let result;
+    unsafe {
+        asm!("svc 0", inout("r0") number => result, in("r1") request);
+    }
+    result
+}
+
+// SVCall handlerから呼ばれる。frameはタスクのスタック(PSP)に積まれた例外フレーム
+// 権限の確認: IPCは呼び出したタスクのケーパビリティ(replyはcall()を受信した相手だけ)、
+// ENTER_PRIVILEGEDとYIELDは確かめない(ENTER_PRIVILEGEDはタスクを保護する仕組みではない)
+pub(crate) fn dispatch(frame: &mut ExceptionFrame) {
+    match frame.r0() {
+        SYSCALL_ENTER_PRIVILEGED => {
+            // ハンドラモードでCONTROL.nPRIVを書き換えると、スレッドモードに戻ったときに有効になる
+            let mut ctrl = control::read();
+            ctrl.set_npriv(control::Npriv::Privileged);
+            unsafe { control::write(ctrl) };
+            // PRIMASKは例外から戻っても変わらないので、タスクは割り込み禁止で戻る
+            cortex_m::interrupt::disable();
+        }
+        SYSCALL_IPC_SEND | SYSCALL_IPC_RECEIVE | SYSCALL_IPC_CALL | SYSCALL_IPC_REPLY => {
+            ipc::handle(frame);
+        }
+        _ => {
+            // SYSCALL_YEILD: PendSVでカーネルに戻る
+            SCB::set_pendsv();
+        }
+    }
+}
@@ -1,8 +1,24 @@
-use crate::{mutex::Mutex, systick};
+// カーネルの時間の基準(tick)
+// SysTick(init)か、RP2040のTIMERのALARM0(init_timer)のどちらかで一定周期の割り込みを発生させて数える。
+// TIMERを使うと、tickless idleで次の期限まで(最長で約35分)眠れる。
+
+use crate::clock;
+use crate::idle::{self, SleepMode};
+use crate::power::{self, PowerState};
+use crate::task::{self, TaskHandle};
+pub use crate::tick::is_expired_at;
+use crate::tick::{catch_up, compensate, plan_sleep};
+use crate::timer_wheel::{TimerId, TimerWheel};
+use crate::{critical_section::IrqMutex, systick};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::asm::{wfe, wfi};
 use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::{NVIC, SYST};
 use cortex_m_rt::exception;
 use defmt::info;
-use rp2040_hal::pac::SCB;
+#[cfg(feature = "timer-irq")]
+use rp2040_hal::pac::interrupt;
+use rp2040_hal::pac::{self, SCB};
 
 struct Count(u32);
 
@@ -13,12 +29,46 @@ impl Count {
     fn incr(&mut self) {
         self.0 = self.0.wrapping_add(1);
     }
+    fn add(&mut self, ticks: u32) {
+        self.0 = self.0.wrapping_add(ticks);
+    }
+}
+
+// IrqMutex::new, Count::new が const fn なので、static変数を初期化できる
+// タスクとSysTick handlerの両方からアクセスするので、クリティカルセクションで保護する
+static SYSTICK_COUNT: IrqMutex<Count> = IrqMutex::new(Count::new(0));
+
+// タスクの待ちのタイムアウト(遅延、ロックやキューのタイムアウト、ソフトウェアタイマー)。
+// SysTick handlerが時刻を進めて、期限が来たタスクを起こす
+pub const MAX_TIMEOUTS: usize = 32;
+
+struct Timeouts {
+    wheel: TimerWheel<TaskHandle, MAX_TIMEOUTS>,
+    untracked: u32, // ホイールがいっぱいで登録できなかった期限の数。0でなければtickless idleしない
 }
 
-// Mutex::new, Count::new が const fn なので、static変数を初期化できる
-static SYSTICK_COUNT: Mutex<Count> = Mutex::new(Count::new(0));
+static TIMEOUTS: IrqMutex<Timeouts> = IrqMutex::new(Timeouts {
+    wheel: TimerWheel::new(),
+    untracked: 0,
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    SysTick, // コアのクロックで数える。tickless idleで眠れるのは24bitのカウント数まで
+    Timer,   // TIMER(1MHz)のALARM0
+}
+
+// init_timer()したらtrue
+static USE_TIMER: AtomicBool = AtomicBool::new(false);
+
+// 1tickの長さ。SysTickならカウント数、TIMERならマイクロ秒。0ならinit()されていない
+static PERIOD: AtomicU32 = AtomicU32::new(0);
+
+// TIMERで次のtickになる時刻(TIMERの下位32bit)
+static NEXT_ALARM: AtomicU32 = AtomicU32::new(0);
 
 pub fn init(syst: &mut cortex_m::peripheral::SYST, reload: u32) {
+    PERIOD.store(reload + 1, Ordering::Relaxed);
     syst.set_clock_source(SystClkSource::Core);
     syst.set_reload(reload);
     syst.clear_current();
@@ -26,6 +76,25 @@ pub fn init(syst: &mut cortex_m::peripheral::SYST, reload: u32) {
     syst.enable_interrupt();
 }
 
+// TIMERのALARM0でtick_usマイクロ秒ごとに数える。先にclock::init()を呼んでおくこと
+pub fn init_timer(tick_us: u32) {
+    assert!(tick_us > 0 && tick_us <= i32::MAX as u32);
+    PERIOD.store(tick_us, Ordering::Relaxed);
+    USE_TIMER.store(true, Ordering::Relaxed);
+    let next = clock::now_low().wrapping_add(tick_us);
+    NEXT_ALARM.store(next, Ordering::Relaxed);
+    clock::set_alarm(next);
+    unsafe { NVIC::unmask(pac::Interrupt::TIMER_IRQ_0) };
+}
+
+pub fn clock_source() -> ClockSource {
+    if USE_TIMER.load(Ordering::Relaxed) {
+        ClockSource::Timer
+    } else {
+        ClockSource::SysTick
+    }
+}
+
 fn count_incr() {
     // lockを取って、UnsafeCell<>の中の値を操作する(mutでなくてもOK:内部可変性)
     SYSTICK_COUNT.lock().incr();
@@ -35,12 +104,196 @@ pub fn count_get() -> u32 {
     SYSTICK_COUNT.lock().0
 }
 
+// 今からticks後の時刻
+pub fn deadline(ticks: u32) -> u32 {
+    count_get().wrapping_add(ticks)
+}
+
+// deadlineにtaskを起こす。いっぱいならNone(スケジューラが巡回するときに期限を確認する)
+pub(crate) fn wake_at(deadline: u32, task: TaskHandle) -> Option<TimerId> {
+    let mut timeouts = TIMEOUTS.lock();
+    let id = timeouts.wheel.insert(deadline, task);
+    if id.is_none() {
+        timeouts.untracked += 1;
+    }
+    id
+}
+
+// wake_at()の登録を取り消す。登録できなかった(None)期限も、ここで取り消す
+pub(crate) fn cancel_wake(id: Option<TimerId>) {
+    let mut timeouts = TIMEOUTS.lock();
+    match id {
+        Some(id) => {
+            timeouts.wheel.cancel(id);
+        }
+        None => {
+            debug_assert!(timeouts.untracked > 0, "untracked deadline is not counted");
+            timeouts.untracked = timeouts.untracked.saturating_sub(1);
+        }
+    }
+}
+
+// deadlineを過ぎたか。カウンタがラップアラウンドしても正しく比較する
+pub fn is_expired(deadline: u32) -> bool {
+    is_expired_at(deadline, count_get())
+}
+
+// 全てのタスクが待っているときに、カーネルから呼ぶ(特権モード)。
+// 次の期限まで眠り、起きたら眠っていた分のtickを数える。
+pub(crate) fn idle() {
+    let period = PERIOD.load(Ordering::Relaxed);
+    // 割り込みを禁止していても、割り込みが保留されればwfiから起きる
+    cortex_m::interrupt::disable();
+    let ticks = {
+        let timeouts = TIMEOUTS.lock();
+        if task::wake_pending() {
+            0
+        } else if timeouts.untracked > 0 {
+            1
+        } else {
+            let now = count_get();
+            timeouts
+                .wheel
+                .next_expiry()
+                .map_or(u32::MAX, |next| next.wrapping_sub(now))
+        }
+    };
+    if ticks == 0 {
+        unsafe { cortex_m::interrupt::enable() };
+        return;
+    }
+    let mode = match (idle::policy().choose(ticks), clock_source()) {
+        // deep sleepでSysTickが止まると数え直せないので、TIMERのときだけ
+        (SleepMode::DeepSleep, ClockSource::SysTick) => SleepMode::Tickless,
+        (mode, _) => mode,
+    };
+    let mut scb = unsafe { cortex_m::Peripherals::steal() }.SCB;
+    match mode {
+        SleepMode::Wfi => wfi(),
+        SleepMode::Wfe => {
+            // 割り込みを禁止していると、保留された割り込みではwfeから起きない。
+            // SEVONPENDで保留をイベントにする(すでに保留されている割り込みはイベントにならないので眠らない)
+            scb.set_sevonpend();
+            if !interrupt_pending() {
+                wfe();
+            }
+            scb.clear_sevonpend();
+        }
+        SleepMode::Tickless => match clock_source() {
+            ClockSource::SysTick => idle_systick(period, ticks),
+            ClockSource::Timer => idle_timer(period, ticks),
+        },
+        // deep sleepはTIMERのときだけ
+        SleepMode::DeepSleep => match power::choose_state(ticks) {
+            PowerState::Run => idle_timer(period, ticks),
+            PowerState::Sleep => {
+                power::gate_clocks();
+                scb.set_sleepdeep();
+                idle_timer(period, ticks);
+                scb.clear_sleepdeep();
+            }
+            PowerState::Dormant => {
+                // TIMERも止まっていたので、RTCで測った時間だけ進める。
+                // 過ぎたtickは、アラームをセットし直すとTIMER_IRQ_0が数える
+                clock::advance(power::dormant());
+                clock::set_alarm(NEXT_ALARM.load(Ordering::Relaxed));
+            }
+        },
+    }
+    unsafe { cortex_m::interrupt::enable() };
+}
+
+// 例外か割り込みが保留されているか(ICSR.ISRPENDING)
+fn interrupt_pending() -> bool {
+    unsafe { (*SCB::PTR).icsr.read() & (1 << 22) != 0 }
+}
+
+// 眠っている間はSysTickのリロード値を延ばし、起きたら補正する
+fn idle_systick(period: u32, ticks: u32) {
+    let mut syst = unsafe { cortex_m::Peripherals::steal() }.SYST;
+    let remaining = SYST::get_current() + 1;
+    let plan = if period == 0 {
+        None
+    } else {
+        plan_sleep(period, remaining, ticks)
+    };
+    match plan {
+        None => wfi(),
+        Some(sleep) => {
+            syst.disable_counter();
+            syst.set_reload(sleep.cycles - 1);
+            syst.clear_current();
+            syst.enable_counter();
+            wfi();
+            let current = SYST::get_current();
+            let elapsed = if syst.has_wrapped() {
+                sleep.cycles + (sleep.cycles - 1 - current)
+            } else {
+                sleep.cycles - 1 - current
+            };
+            syst.disable_counter();
+            let (ticks, next) = compensate(period, remaining, sleep, elapsed);
+            SYSTICK_COUNT.lock().add(ticks);
+            wake_expired();
+            // 次のtickの残りから数え直して、その後は1tickごとに戻す
+            syst.set_reload(next - 1);
+            syst.clear_current();
+            syst.enable_counter();
+            syst.set_reload(period - 1);
+        }
+    }
+}
+
+// 眠っている間はアラームを次の期限に合わせる。TIMERは止まらないので、補正はTIMER_IRQ_0が数え直すだけ
+fn idle_timer(period: u32, ticks: u32) {
+    let next = NEXT_ALARM.load(Ordering::Relaxed);
+    let ticks = ticks.min(i32::MAX as u32 / period);
+    if ticks >= 2 {
+        clock::set_alarm(next.wrapping_add((ticks - 1) * period));
+    }
+    wfi();
+    // 次のtickに戻す。過ぎていれば、割り込みを許可したときにTIMER_IRQ_0が過ぎた分を数える
+    clock::set_alarm(next);
+}
+
+// 期限が来たタスクを起こす
+// タスクの実行中なら、PendSVをセットする⇒全ての割り込みが終わったあと PendSV handlerが呼ばれる
+// カーネルの実行中(tickless idleなど)は切り替えるタスクがない
+fn wake_expired() {
+    let now = count_get();
+    TIMEOUTS.lock().wheel.advance(now, |task| task.wake());
+    if TaskHandle::current().is_some() {
+        SCB::set_pendsv();
+    }
+}
+
 // SysTick handler
 // systick counterを増やす
-// PendSVをセットする⇒全ての割り込みが終わったあと PendSV handlerが呼ばれる
 #[exception]
 fn SysTick() {
     info!("SysTick:{}", systick::count_get());
     systick::count_incr();
-    SCB::set_pendsv();
+    wake_expired();
+}
+
+// TIMERのALARM0 handler
+// feature "timer-irq"(デフォルト)を無効にすると、TIMER_IRQ_0はアプリケーションが定義できる。
+// その場合、init_timer()を使うならアプリケーションのTIMER_IRQ_0からon_timer_irq()を呼ぶこと
+#[cfg(feature = "timer-irq")]
+#[interrupt]
+fn TIMER_IRQ_0() {
+    on_timer_irq();
+}
+
+// 過ぎたtickを数えて、次のtickにアラームをセットし直す
+pub fn on_timer_irq() {
+    clock::clear_alarm();
+    let period = PERIOD.load(Ordering::Relaxed);
+    let (ticks, next) = catch_up(NEXT_ALARM.load(Ordering::Relaxed), clock::now_low(), period);
+    NEXT_ALARM.store(next, Ordering::Relaxed);
+    clock::set_alarm(next);
+    if ticks > 0 {
+        SYSTICK_COUNT.lock().add(ticks);
+        wake_expired();
+    }
 }
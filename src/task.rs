@@ -1,23 +1,170 @@
+use crate::capability::{self, CapId, Capability, CapabilityTable, Object, Rights};
+use crate::critical_section::IrqMutex;
+use crate::ipc::{self, Endpoint};
+use crate::linked_list::ListItem;
+use crate::notification::Notification;
+use crate::timer_wheel::TimerId;
+#[cfg(feature = "alloc")]
+use crate::tlsf::TaskHeapStats;
+use crate::wake::{self, Wake};
 use crate::{syscall, systick};
 use core::arch::asm;
+use core::cell::UnsafeCell;
 use core::marker::PhantomData;
 use core::mem::MaybeUninit;
+use core::ops::Range;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
 use cortex_m_rt::ExceptionFrame;
 use defmt::info;
 
 enum TaskState {
     Ready,
     Blocked,
+    Stopped, // 二度と実行しない(メモリ不足で止めたタスクなど)
 }
 
 pub struct Task<'a> {
     sp: usize,
-    regs: [u32; 8], // r4, r5, r6, r7, r8, r9, r10, r11
+    stack_bottom: usize, // スタックの一番下のアドレス
+    regs: [u32; 8],      // r4, r5, r6, r7, r8, r9, r10, r11
     state: TaskState,
     wait_until: Option<u32>,
+    timeout: Option<TimerId>, // wait_untilにタスクを起こすタイミングホイールの登録
+    woken: AtomicBool,        // 待ちリストから起こされた(割り込みハンドラからも書き込まれる)
+    notification: IrqMutex<Notification>,
+    ipc: Endpoint,
+    caps: CapabilityTable,
+    lock_levels: AtomicU32, // 保持しているロックのレベル(lock_order::HeldLevels)
+    #[cfg(feature = "alloc")]
+    heap: IrqMutex<TaskHeapStats>, // ヒープの使用量と上限
     marker: PhantomData<&'a u8>,
 }
 
+// 実行中のタスク。カーネルがタスクに切り替える直前にセットする
+static CURRENT: AtomicPtr<Task<'static>> = AtomicPtr::new(ptr::null_mut());
+
+// スケジューラが全てのタスクを確認し終わるまでに、起こされたタスクがある。
+// セットされていればtickless idleで眠らない
+static WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn wake_pending() -> bool {
+    WAKE_PENDING.load(Ordering::Acquire)
+}
+
+pub(crate) fn clear_wake_pending() {
+    WAKE_PENDING.store(false, Ordering::Release);
+}
+
+// 待ちリストなどからタスクを参照するためのハンドル
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TaskHandle(NonNull<Task<'static>>);
+
+unsafe impl Send for TaskHandle {}
+unsafe impl Sync for TaskHandle {}
+
+impl TaskHandle {
+    // タスクの中から呼ばれたときだけSomeを返す
+    pub fn current() -> Option<Self> {
+        NonNull::new(CURRENT.load(Ordering::Acquire)).map(TaskHandle)
+    }
+
+    // タスクを識別する番号(ログ出力用)
+    pub fn id(&self) -> usize {
+        self.0.as_ptr() as usize
+    }
+
+    // AtomicPtrに入れて割り込みハンドラと共有するため
+    pub(crate) fn as_ptr(&self) -> *mut Task<'static> {
+        self.0.as_ptr()
+    }
+
+    pub(crate) fn from_ptr(ptr: *mut Task<'static>) -> Option<Self> {
+        NonNull::new(ptr).map(TaskHandle)
+    }
+
+    // タスクが動かない場所(スケジューラのリストの要素)に置かれてから作ること。
+    // アプリケーションはScheduler::push_back()が返すハンドルを使う
+    pub(crate) fn from_task(task: &Task<'_>) -> Self {
+        TaskHandle(NonNull::from(task).cast())
+    }
+
+    // 待ち状態のタスクを起こす。割り込みハンドラからも呼べる
+    pub fn wake(&self) {
+        unsafe { self.0.as_ref() }
+            .woken
+            .store(true, Ordering::Release);
+        WAKE_PENDING.store(true, Ordering::Release);
+    }
+
+    // タスクを待ち状態にする。実際に切り替わるのは syscall::back_to_kernel() のとき
+    // 実行中のタスク自身から呼ぶこと(その間カーネルは動いていない)
+    pub(crate) fn block(&self, deadline: Option<u32>) {
+        let task = unsafe { &mut *self.0.as_ptr() };
+        task.cancel_timeout();
+        task.woken.store(false, Ordering::Release);
+        task.wait_until = deadline;
+        task.timeout = deadline.and_then(|d| systick::wake_at(d, *self));
+        task.state = TaskState::Blocked;
+    }
+
+    // block()したが待つ必要がなくなった場合に元に戻す
+    pub(crate) fn unblock(&self) {
+        let task = unsafe { &mut *self.0.as_ptr() };
+        task.cancel_timeout();
+        task.wait_until = None;
+        task.state = TaskState::Ready;
+    }
+
+    // 実行中のタスク自身を止める。持っているロックなどは開放されない
+    #[cfg_attr(not(feature = "alloc"), allow(dead_code))] // 今はメモリ不足のときだけ使う
+    pub(crate) fn stop(&self) -> ! {
+        let task = unsafe { &mut *self.0.as_ptr() };
+        task.cancel_timeout();
+        task.wait_until = None;
+        task.state = TaskState::Stopped;
+        loop {
+            syscall::back_to_kernel();
+        }
+    }
+
+    pub(crate) fn notification(&self) -> &IrqMutex<Notification> {
+        &unsafe { self.0.as_ref() }.notification
+    }
+
+    // IPCの状態。SVCall handlerとカーネルだけが使う
+    pub(crate) fn ipc(&self) -> *mut Endpoint {
+        unsafe { ptr::addr_of_mut!((*self.0.as_ptr()).ipc) }
+    }
+
+    // タスクは削除されないので'static
+    pub(crate) fn lock_levels(self) -> &'static AtomicU32 {
+        unsafe { &(*self.0.as_ptr()).lock_levels }
+    }
+
+    // タスクのスタックの範囲。システムコールの引数のアドレスの確認に使う
+    pub(crate) fn stack(&self) -> Range<usize> {
+        let bottom = unsafe { self.0.as_ref() }.stack_bottom;
+        bottom..bottom + STACK_SIZE
+    }
+
+    // システムコールの権限の確認に使う
+    pub(crate) fn capabilities(&self) -> &CapabilityTable {
+        &unsafe { self.0.as_ref() }.caps
+    }
+
+    // グローバルアロケータだけが使う
+    #[cfg(feature = "alloc")]
+    pub(crate) fn heap(&self) -> &IrqMutex<TaskHeapStats> {
+        &unsafe { self.0.as_ref() }.heap
+    }
+
+    // 待ち状態のタスクのスタックに積まれた例外フレーム。r0を書き換えるとsvcの戻り値になる
+    pub(crate) fn frame(&self) -> *mut ExceptionFrame {
+        unsafe { (*self.0.as_ptr()).sp as *mut ExceptionFrame }
+    }
+}
+
 pub const STACK_SIZE: usize = 1024;
 
 #[repr(align(8))]
@@ -40,37 +187,146 @@ impl<'a> Task<'a> {
 
         Task {
             sp,
+            stack_bottom: stack.0.as_ptr() as usize,
             regs: [0; 8],
             state: TaskState::Ready,
             wait_until: None,
+            timeout: None,
+            woken: AtomicBool::new(false),
+            notification: IrqMutex::new(Notification::new()),
+            ipc: Endpoint::new(),
+            caps: CapabilityTable::new(),
+            lock_levels: AtomicU32::new(0),
+            #[cfg(feature = "alloc")]
+            heap: IrqMutex::new(TaskHeapStats::new()),
             marker: PhantomData,
         }
     }
 
-    pub fn exec(&mut self) {
+    // ケーパビリティを与える。スケジューラに登録する前にカーネルが呼ぶ
+    pub fn grant(&mut self, object: Object, rights: Rights) -> Result<CapId, capability::Error> {
+        self.caps.grant(object, rights)
+    }
+
+    pub fn revoke(&mut self, id: CapId) -> Result<Capability, capability::Error> {
+        self.caps.revoke(id)
+    }
+
+    // 実行したか、実行可能になったらtrue
+    pub fn exec(&mut self) -> bool {
         match self.state {
             TaskState::Ready => {
                 info!("execute task {:x}", self.sp);
+                CURRENT.store((self as *mut Self).cast(), Ordering::Release);
                 self.sp = execute_task(self.sp as u32, &mut self.regs as *mut u32 as u32) as usize;
+                CURRENT.store(ptr::null_mut(), Ordering::Release);
+                true
             }
             TaskState::Blocked => {
                 info!("task is blocked{:x}", self.sp);
-                if let Some(until) = self.wait_until {
-                    if systick::count_get() >= until {
-                        self.wait_until = None;
-                        self.state = TaskState::Ready;
-                    }
+                // 待ちリストから起こされたか、タイムアウトしたら実行可能にする
+                // タイムアウトはSysTick handlerが起こす。タイミングホイールがいっぱいで登録できなかったときは、ここで期限を確認する
+                let handle = TaskHandle::from_task(self);
+                let woken = self.woken.swap(false, Ordering::AcqRel);
+                let expired = self.wait_until.is_some_and(systick::is_expired);
+                match wake::check(woken, expired, ipc::is_waiting(handle)) {
+                    Wake::Stay => return false,
+                    Wake::Woken => {}
+                    // タイムアウトした場合は、IPCの待ちを取り消す
+                    Wake::Expired => ipc::cancel(handle),
                 }
+                self.cancel_timeout();
+                self.wait_until = None;
+                self.state = TaskState::Ready;
+                true
             }
+            TaskState::Stopped => false,
         }
     }
 
     pub fn wait_until(&mut self, tick: u32) {
         // info!("wait_until({})", tick);
-        self.wait_until = Some(tick);
-        self.state = TaskState::Blocked;
+        TaskHandle::from_task(self).block(Some(tick));
         syscall::back_to_kernel();
     }
+
+    // 期限がなければ何もしない。ホイールに登録できなかった期限(timeoutがNone)も取り消す
+    fn cancel_timeout(&mut self) {
+        if self.wait_until.is_some() {
+            systick::cancel_wake(self.timeout.take());
+        }
+    }
+}
+
+// staticに置くタスクのスタック。起動時にゼロで初期化しないように、.uninit.STACKSに置く
+// static_task!()が宣言する
+pub struct TaskStack(UnsafeCell<AlignedStack>);
+
+impl TaskStack {
+    pub const fn new() -> Self {
+        TaskStack(UnsafeCell::new(AlignedStack(MaybeUninit::uninit())))
+    }
+}
+
+impl Default for TaskStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl Sync for TaskStack {}
+
+// staticに置くタスク。スタックとスケジューラのリストの要素を持つので、ヒープを使わずに登録できる
+// static_task!()で宣言する
+//
+// static_task!(APP);
+// SCHEDULER.write().push_back(APP.init(app_main));
+pub struct StaticTask {
+    stack: &'static TaskStack,
+    item: UnsafeCell<MaybeUninit<ListItem<'static, Task<'static>>>>,
+    taken: AtomicBool,
+}
+
+impl StaticTask {
+    /// # Safety
+    /// stackを他のStaticTaskに渡してはいけない
+    pub const unsafe fn new(stack: &'static TaskStack) -> Self {
+        StaticTask {
+            stack,
+            item: UnsafeCell::new(MaybeUninit::uninit()),
+            taken: AtomicBool::new(false),
+        }
+    }
+
+    // タスクを作って、スケジューラに渡すリストの要素を返す。2度目に呼ぶとpanicする
+    pub fn init(&'static self, app_fn: fn() -> !) -> &'static mut ListItem<'static, Task<'static>> {
+        // M0+にはswapがないので、割り込みを禁止して確認する
+        critical_section::with(|_| {
+            assert!(
+                !self.taken.load(Ordering::Acquire),
+                "task is already initialized"
+            );
+            self.taken.store(true, Ordering::Release);
+        });
+        unsafe {
+            let stack = &mut *self.stack.0.get();
+            (*self.item.get()).write(ListItem::new(Task::new(stack, app_fn)))
+        }
+    }
+}
+
+unsafe impl Sync for StaticTask {}
+
+// StaticTaskと、そのスタック(.uninit.STACKS)を宣言する
+#[macro_export]
+macro_rules! static_task {
+    ($vis:vis $name:ident) => {
+        $vis static $name: $crate::task::StaticTask = {
+            #[link_section = ".uninit.STACKS"]
+            static STACK: $crate::task::TaskStack = $crate::task::TaskStack::new();
+            unsafe { $crate::task::StaticTask::new(&STACK) }
+        };
+    };
 }
 
 #[inline(never)]
@@ -0,0 +1,234 @@
+// tickの計算。期限の比較と、tickless idleで眠る長さと起きたときの補正、Dormantで止まっていた時間
+// ハードウェアには触らないので、単体でテストできる
+//
+// ❯ rustc --test src/tick.rs
+
+// SysTickのリロード値は24bit
+pub const MAX_RELOAD: u32 = 0x00ff_ffff;
+
+// 時刻nowにdeadlineを過ぎているか
+pub fn is_expired_at(deadline: u32, now: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+// 期限付きで待って戻ってきたときに、nowにタイムアウトしているか
+// 何で起こされたかには関係なく、期限を過ぎたかで決める。期限がなければ(無期限に待った)false
+pub fn timed_out(deadline: Option<u32>, now: u32) -> bool {
+    deadline.is_some_and(|deadline| is_expired_at(deadline, now))
+}
+
+// tickless idleで眠る長さ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sleep {
+    pub ticks: u32,  // 次の期限までのtick数
+    pub cycles: u32, // 今からタイマーが満了するまでのカウント数
+}
+
+// period: 1tickのカウント数、remaining: 今のtickの残りカウント数、ticks: 次の期限までのtick数
+// 次のtickより先まで眠れなければNone
+pub fn plan_sleep(period: u32, remaining: u32, ticks: u32) -> Option<Sleep> {
+    let max_ticks = (MAX_RELOAD - remaining.min(MAX_RELOAD)) / period + 1;
+    let ticks = ticks.min(max_ticks);
+    if ticks < 2 {
+        return None;
+    }
+    Some(Sleep {
+        ticks,
+        cycles: remaining + (ticks - 1) * period,
+    })
+}
+
+// 起きたときの補正。elapsedは眠り始めてから経ったカウント数
+// 戻り値は(カウンタに足すtick数, 次のtickまでの残りカウント数)
+pub fn compensate(period: u32, remaining: u32, sleep: Sleep, elapsed: u32) -> (u32, u32) {
+    if elapsed >= sleep.cycles {
+        // 最後まで眠った。満了したtickはこのあとSysTick handlerが数える
+        let over = elapsed - sleep.cycles;
+        (sleep.ticks - 1 + over / period, period - over % period)
+    } else {
+        // 他の割り込みで起きた。眠る前のtickの始めから数える
+        let since_tick = period - remaining + elapsed;
+        (since_tick / period, period - since_tick % period)
+    }
+}
+
+// TIMERでnextのtickになるのを待っている。nowまでに過ぎたtick数と、次のtickの時刻
+pub fn catch_up(next: u32, now: u32, period: u32) -> (u32, u32) {
+    if (now.wrapping_sub(next) as i32) < 0 {
+        return (0, next);
+    }
+    let ticks = now.wrapping_sub(next) / period + 1;
+    (ticks, next.wrapping_add(ticks * period))
+}
+
+// RTCの1秒をTIMERのマイクロ秒に
+const RTC_SECOND: u64 = 1_000_000;
+// TIMERとRTCがこれ以上ずれていたら、RTCが設定し直されたとみなす
+const RTC_RESYNC: u64 = 2 * RTC_SECOND;
+
+// Dormantで止まっていたTIMERを、RTC(秒単位)に合わせて進める
+// RTCは秒未満を読めないので、眠る前後の差をとるとDormantに入るたびに最大1秒ずつずれが積み重なる。
+// 最初に読んだRTCとそのときのTIMERを基準にして、基準からのRTCの経過に合わせれば、ずれは常に1秒未満
+pub struct RtcAnchor {
+    anchor: Option<(u64, u64)>, // (RTCの秒, そのときのTIMERのマイクロ秒)
+}
+
+impl RtcAnchor {
+    pub const fn new() -> Self {
+        RtcAnchor { anchor: None }
+    }
+
+    // before/after: Dormantの前後のRTCの秒、now: 眠る前のTIMER。TIMERを進めるマイクロ秒を返す
+    // TIMERは戻さないので、RTCより進んでいれば0
+    pub fn advance(&mut self, before: u64, after: u64, now: u64) -> u64 {
+        let (rtc, timer) = *self.anchor.get_or_insert((before, now));
+        let expected = rtc * RTC_SECOND + now.saturating_sub(timer);
+        if before < rtc || (before * RTC_SECOND).abs_diff(expected) > RTC_RESYNC {
+            self.anchor = Some((before, now));
+            return after.saturating_sub(before) * RTC_SECOND;
+        }
+        (timer + (after - rtc) * RTC_SECOND).saturating_sub(now)
+    }
+}
+
+impl Default for RtcAnchor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        catch_up, compensate, is_expired_at, plan_sleep, timed_out, RtcAnchor, Sleep, MAX_RELOAD,
+        RTC_SECOND,
+    };
+
+    const PERIOD: u32 = 125_000; // 125MHzで1ms
+
+    #[test]
+    fn test_is_expired_at() {
+        assert!(!is_expired_at(10, 9));
+        assert!(is_expired_at(10, 10));
+        assert!(is_expired_at(10, 11));
+        // カウンタのラップアラウンド
+        assert!(!is_expired_at(5, u32::MAX - 5));
+        assert!(is_expired_at(u32::MAX - 5, 5));
+    }
+
+    #[test]
+    fn test_timed_out() {
+        // Condvar::wait()は期限がないので、何で起こされてもタイムアウトではない
+        assert!(!timed_out(None, 100));
+        // 期限の前に(通知などで)起こされた
+        assert!(!timed_out(Some(100), 99));
+        assert!(timed_out(Some(100), 100));
+        assert!(timed_out(Some(u32::MAX), 3));
+    }
+
+    #[test]
+    fn test_plan_sleep() {
+        // 次の期限が今のtickの中なら眠らない
+        assert_eq!(None, plan_sleep(PERIOD, 1000, 1));
+        assert_eq!(None, plan_sleep(PERIOD, 1000, 0));
+        assert_eq!(
+            Some(Sleep {
+                ticks: 10,
+                cycles: 1000 + 9 * PERIOD
+            }),
+            plan_sleep(PERIOD, 1000, 10)
+        );
+        // 24bitのリロード値に収まる長さで切る
+        let sleep = plan_sleep(PERIOD, 1000, u32::MAX).unwrap();
+        assert_eq!(135, sleep.ticks);
+        assert!(sleep.cycles <= MAX_RELOAD);
+        assert!(sleep.cycles + PERIOD > MAX_RELOAD);
+        // 1tickがリロード値の上限に近いと、tickless idleできない
+        assert_eq!(None, plan_sleep(MAX_RELOAD, 1000, 100));
+    }
+
+    #[test]
+    fn test_compensate_full_sleep() {
+        let sleep = plan_sleep(PERIOD, 1000, 10).unwrap();
+        // ちょうど満了した。最後のtickはSysTick handlerが数える
+        assert_eq!((9, PERIOD), compensate(PERIOD, 1000, sleep, sleep.cycles));
+        // 満了してから起きるまでに時間がかかった
+        assert_eq!(
+            (9, PERIOD - 300),
+            compensate(PERIOD, 1000, sleep, sleep.cycles + 300)
+        );
+        assert_eq!(
+            (10, PERIOD - 300),
+            compensate(PERIOD, 1000, sleep, sleep.cycles + PERIOD + 300)
+        );
+    }
+
+    #[test]
+    fn test_compensate_early_wake() {
+        let sleep = plan_sleep(PERIOD, 1000, 10).unwrap();
+        // 眠る前のtickが終わる前に起きた
+        assert_eq!((0, 400), compensate(PERIOD, 1000, sleep, 600));
+        // tickの境目
+        assert_eq!((1, PERIOD), compensate(PERIOD, 1000, sleep, 1000));
+        // 3tick経って、4tick目の途中
+        assert_eq!(
+            (3, PERIOD - 500),
+            compensate(PERIOD, 1000, sleep, 1000 + 2 * PERIOD + 500)
+        );
+        // どこで起きても、眠る前のtickの始めからの経過時間は変わらない
+        for elapsed in (0..sleep.cycles).step_by(9973) {
+            let (ticks, next) = compensate(PERIOD, 1000, sleep, elapsed);
+            assert_eq!(PERIOD - 1000 + elapsed, ticks * PERIOD + (PERIOD - next));
+        }
+    }
+
+    #[test]
+    fn test_catch_up() {
+        // まだ次のtickになっていない(アラームを早めにセットしたときなど)
+        assert_eq!((0, 1000), catch_up(1000, 999, 1000));
+        assert_eq!((1, 2000), catch_up(1000, 1000, 1000));
+        // 眠っていた間のtickをまとめて数える
+        assert_eq!((3, 4000), catch_up(1000, 3500, 1000));
+        // TIMERの下位32bitのラップアラウンド
+        assert_eq!((2, 1500), catch_up(u32::MAX - 499, 600, 1000));
+    }
+
+    // 起きている間と眠っている間を繰り返して、本当の時刻とTIMERのずれを調べる
+    // 時刻はマイクロ秒。RTCは秒未満を切り捨てて読める
+    #[test]
+    fn test_rtc_anchor_bounded_drift() {
+        let mut anchor = RtcAnchor::new();
+        let (mut real, mut timer) = (10_300_000u64, 0u64);
+        let mut seed = 7u64;
+        for _ in 0..1000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            let awake = (seed >> 33) % 3_000_000;
+            let asleep = (seed >> 13) % 5_000_000;
+            real += awake;
+            timer += awake;
+            let before = real / RTC_SECOND;
+            real += asleep;
+            timer += anchor.advance(before, real / RTC_SECOND, timer);
+            // 最初の基準の端数の分(1秒未満)しかずれない
+            assert!(real.abs_diff(timer + 10_300_000) < RTC_SECOND);
+        }
+    }
+
+    #[test]
+    fn test_rtc_anchor() {
+        let mut anchor = RtcAnchor::new();
+        // 最初は前後の差
+        assert_eq!(3 * RTC_SECOND, anchor.advance(100, 103, 5_000_000));
+        // 起きていた0.5秒の後で、RTCは103のままでも基準から数える
+        assert_eq!(
+            2 * RTC_SECOND - 500_000,
+            anchor.advance(103, 105, 8_500_000)
+        );
+        // TIMERのほうが進んでいれば進めない
+        assert_eq!(0, anchor.advance(105, 105, 10_900_000));
+        // RTCが設定し直されたら、基準を取り直す
+        assert_eq!(4 * RTC_SECOND, anchor.advance(5000, 5004, 11_000_000));
+        assert_eq!(RTC_SECOND, anchor.advance(3, 4, 16_000_000));
+        assert_eq!(RTC_SECOND, anchor.advance(4, 5, 17_000_000));
+    }
+}
@@ -0,0 +1,185 @@
+// ソフトウェアタイマー
+// 指定したtick数が経つと、タイマーサービスタスクの中でコールバックを呼ぶ。
+// ワンショット(1回だけ)と自動リロード(周期的)がある。
+// start/stop/reset/change_periodはコマンドキュー経由でサービスタスクに送るので、
+// タスクからも割り込みハンドラ(_from_isr)からも呼べる。
+// コールバックはサービスタスクの中で呼ばれるので、待つ処理(コマンドキューへの送信も)をしてはいけない。
+// 動いているタイマーはサービスタスクのタイミングホイールに登録するので、開始も満了もO(1)。
+//
+// fn blink(_timer: &'static Timer) { led::toggle(); }
+// static BLINK: Timer = Timer::new(5, Mode::AutoReload, blink);
+// BLINK.start(None)?;
+// // timer::service をタスクとして登録しておく
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use defmt::warn;
+
+use crate::queue::Queue;
+use crate::systick;
+use crate::timer_wheel::{Periodic, TimerId, Timers};
+
+pub const MAX_TIMERS: usize = 16; // 同時に動かせるタイマーの数
+const COMMAND_QUEUE_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    OneShot,
+    AutoReload,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    QueueFull, // コマンドキューがいっぱい(タスクからはタイムアウト)
+}
+
+pub struct Timer {
+    period: AtomicU32,
+    mode: Mode,
+    callback: fn(&'static Timer),
+    active: AtomicBool,        // サービスタスクだけが書き込む
+    id: Cell<Option<TimerId>>, // ホイールの登録。サービスタスクだけが使う
+}
+
+// idはサービスタスクだけが読み書きする
+unsafe impl Sync for Timer {}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Start, // 動いていれば、今から数え直す(reset)
+    Stop,
+    ChangePeriod(u32), // 周期を変えて、今から数え直す
+}
+
+#[derive(Clone, Copy)]
+struct Command {
+    timer: &'static Timer,
+    op: Op,
+    issued_at: u32, // コマンドを出したtick。サービスタスクが処理するまでの遅れを補正する
+}
+
+static COMMANDS: Queue<Command, COMMAND_QUEUE_LEN> = Queue::new();
+
+impl Timer {
+    pub const fn new(period: u32, mode: Mode, callback: fn(&'static Timer)) -> Self {
+        Timer {
+            period: AtomicU32::new(period),
+            mode,
+            callback,
+            active: AtomicBool::new(false),
+            id: Cell::new(None),
+        }
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period.load(Ordering::Relaxed)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    // timeoutはコマンドキューに空きができるまで待つtick数、Noneなら無期限に待つ
+    pub fn start(&'static self, timeout: Option<u32>) -> Result<(), Error> {
+        self.command(Op::Start, timeout)
+    }
+
+    pub fn stop(&'static self, timeout: Option<u32>) -> Result<(), Error> {
+        self.command(Op::Stop, timeout)
+    }
+
+    pub fn reset(&'static self, timeout: Option<u32>) -> Result<(), Error> {
+        self.command(Op::Start, timeout)
+    }
+
+    // 止まっていれば開始する
+    pub fn change_period(&'static self, period: u32, timeout: Option<u32>) -> Result<(), Error> {
+        self.command(Op::ChangePeriod(period), timeout)
+    }
+
+    pub fn start_from_isr(&'static self) -> Result<(), Error> {
+        self.command_from_isr(Op::Start)
+    }
+
+    pub fn stop_from_isr(&'static self) -> Result<(), Error> {
+        self.command_from_isr(Op::Stop)
+    }
+
+    pub fn reset_from_isr(&'static self) -> Result<(), Error> {
+        self.command_from_isr(Op::Start)
+    }
+
+    pub fn change_period_from_isr(&'static self, period: u32) -> Result<(), Error> {
+        self.command_from_isr(Op::ChangePeriod(period))
+    }
+
+    fn command(&'static self, op: Op, timeout: Option<u32>) -> Result<(), Error> {
+        COMMANDS
+            .send(self.make_command(op), timeout)
+            .map_err(|_| Error::QueueFull)
+    }
+
+    fn command_from_isr(&'static self, op: Op) -> Result<(), Error> {
+        COMMANDS
+            .send_from_isr(self.make_command(op))
+            .map_err(|_| Error::QueueFull)
+    }
+
+    fn make_command(&'static self, op: Op) -> Command {
+        Command {
+            timer: self,
+            op,
+            issued_at: systick::count_get(),
+        }
+    }
+}
+
+impl Periodic for Timer {
+    fn period(&self) -> u32 {
+        self.period()
+    }
+
+    fn auto_reload(&self) -> bool {
+        self.mode == Mode::AutoReload
+    }
+
+    fn id(&self) -> &Cell<Option<TimerId>> {
+        &self.id
+    }
+
+    fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Release);
+    }
+}
+
+fn apply(timers: &mut Timers<Timer, MAX_TIMERS>, command: Command) {
+    let timer = command.timer;
+    match command.op {
+        Op::Stop => return timers.stop(timer),
+        Op::ChangePeriod(period) => timer.period.store(period, Ordering::Relaxed),
+        Op::Start => {}
+    }
+    if !timers.start(timer, command.issued_at) {
+        warn!("timer: too many active timers");
+    }
+}
+
+// タイマーサービスタスクの本体。Task::new(stack, timer::service)として登録する
+pub fn service() -> ! {
+    let mut timers: Timers<Timer, MAX_TIMERS> = Timers::new();
+    loop {
+        // 次のタイマーが満了するまで、コマンドを待つ
+        let timeout = timers.next_timeout(systick::count_get());
+        if let Ok(command) = COMMANDS.recv(timeout) {
+            apply(&mut timers, command);
+        }
+        while let Ok(command) = COMMANDS.try_recv() {
+            apply(&mut timers, command);
+        }
+        timers.expire(systick::count_get(), |timer| (timer.callback)(timer));
+    }
+}
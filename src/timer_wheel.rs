@@ -0,0 +1,730 @@
+// 階層タイミングホイール
+// 期限(tick)ごとに値を登録し、時刻を進めると期限が来た値を取り出す。
+// 登録と取り消しはO(1)。ソート済みリストのように登録のたびに並べ替えない。
+//
+// 64スロット×4階層。階層0は1tick、階層1は64tick、階層2は4096tick、階層3は262144tick単位。
+// 遠い期限は上の階層に入れておき、その階層のスロットの番が来たら下の階層に入れ直す(繰り下げ)。
+// 1tickの処理は、期限が来た値と繰り下げる値の数だけ。2^24tickより先の期限は何度か繰り下げる。
+//
+// ノードは固定長の配列に持ち、スロットごとに添字で双方向リストをつなぐ(アロケータは使わない)。
+// Timersはホイールの上で、ソフトウェアタイマーの開始・停止と自動リロードを扱う(timer::serviceの中身)。
+//
+// ❯ rustc --test src/timer_wheel.rs
+
+use core::cell::Cell;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const MAX_DELTA: u32 = 1 << (SLOT_BITS * LEVELS as u32);
+const NIL: u16 = u16::MAX;
+
+// insert()が返す登録番号。取り消しに使う。取り消し済み・期限切れの番号は無視される
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u16,
+}
+
+struct Node<T> {
+    value: Option<T>,
+    expiry: u32,
+    prev: u16,
+    next: u16,
+    slot: u16, // 階層 * SLOTS + スロット番号
+    generation: u16,
+}
+
+pub struct TimerWheel<T, const CAP: usize> {
+    nodes: [Node<T>; CAP],
+    heads: [[u16; SLOTS]; LEVELS],
+    free: u16,   // 取り消したノードのリスト
+    unused: u16, // まだ1度も使っていないノードの先頭
+    now: u32,    // 処理済みの時刻
+    len: usize,
+}
+
+impl<T, const CAP: usize> TimerWheel<T, CAP> {
+    pub const fn new() -> Self {
+        assert!(CAP < NIL as usize);
+        TimerWheel {
+            nodes: [const {
+                Node {
+                    value: None,
+                    expiry: 0,
+                    prev: NIL,
+                    next: NIL,
+                    slot: 0,
+                    generation: 0,
+                }
+            }; CAP],
+            heads: [[NIL; SLOTS]; LEVELS],
+            free: NIL,
+            unused: 0,
+            now: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    // 空のホイールの時刻をnowに合わせる。進めるのと違い、離れていても時間がかからない
+    pub fn set_now(&mut self, now: u32) {
+        debug_assert!(self.is_empty());
+        self.now = now;
+    }
+
+    // expiryにvalueを取り出すように登録する。過ぎた時刻なら次のtickで取り出す。いっぱいならNone
+    pub fn insert(&mut self, expiry: u32, value: T) -> Option<TimerId> {
+        let index = self.alloc()?;
+        let node = &mut self.nodes[index as usize];
+        node.value = Some(value);
+        node.expiry = expiry;
+        let generation = node.generation;
+        self.place(index, 1);
+        self.len += 1;
+        Some(TimerId { index, generation })
+    }
+
+    // 取り出される前なら登録を消して値を返す
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let node = self.nodes.get(id.index as usize)?;
+        if node.generation != id.generation || node.value.is_none() {
+            return None;
+        }
+        self.unlink(id.index);
+        Some(self.release(id.index))
+    }
+
+    // 次に値を取り出すか、上の階層から繰り下げる時刻。空ならNone
+    // tickless idleで、この時刻まで眠ってよい
+    pub fn next_expiry(&self) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut next: Option<u32> = None; // nowからのtick数
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            let current = self.now >> shift;
+            if let Some(k) = (1..=SLOTS as u32)
+                .find(|k| self.heads[level][current.wrapping_add(*k) as usize % SLOTS] != NIL)
+            {
+                let delta = (current.wrapping_add(k) << shift).wrapping_sub(self.now);
+                next = Some(next.map_or(delta, |n| n.min(delta)));
+            }
+        }
+        next.map(|delta| self.now.wrapping_add(delta))
+    }
+
+    // 時刻をnowまで進めて、期限が来た値をfに渡す
+    // 何も起きない時刻は飛ばすので、しばらく進めなかった(眠っていた)ときも処理は値の数だけ
+    pub fn advance(&mut self, now: u32, mut f: impl FnMut(T)) {
+        while (now.wrapping_sub(self.now) as i32) > 0 {
+            if now.wrapping_sub(self.now) > 1 {
+                match self.next_expiry() {
+                    Some(next) if now.wrapping_sub(next) as i32 >= 0 => {
+                        self.now = next.wrapping_sub(1)
+                    }
+                    _ => {
+                        self.now = now;
+                        break;
+                    }
+                }
+            }
+            self.tick(&mut f);
+        }
+    }
+
+    fn tick(&mut self, f: &mut impl FnMut(T)) {
+        self.now = self.now.wrapping_add(1);
+        let t = self.now;
+        // 上の階層から順に、番が来たスロットを下の階層に入れ直す
+        for level in (1..LEVELS).rev() {
+            let shift = SLOT_BITS * level as u32;
+            if t & ((1 << shift) - 1) == 0 {
+                let mut i = self.take(level, (t >> shift) as usize % SLOTS);
+                while i != NIL {
+                    let next = self.nodes[i as usize].next;
+                    self.place(i, 0);
+                    i = next;
+                }
+            }
+        }
+        let mut i = self.take(0, t as usize % SLOTS);
+        while i != NIL {
+            let next = self.nodes[i as usize].next;
+            if self.nodes[i as usize].expiry.wrapping_sub(t) as i32 <= 0 {
+                f(self.release(i));
+            } else {
+                // MAX_DELTAより先の期限。もう一度上の階層に入れる
+                self.place(i, 0);
+            }
+            i = next;
+        }
+    }
+
+    // 期限に応じた階層とスロットに入れる。min_deltaは今から最短で何tick後に取り出すか
+    fn place(&mut self, index: u16, min_delta: u32) {
+        let expiry = self.nodes[index as usize].expiry;
+        let delta = (expiry.wrapping_sub(self.now) as i32).max(min_delta as i32) as u32;
+        let delta = delta.min(MAX_DELTA - 1);
+        let target = self.now.wrapping_add(delta);
+        let mut level = 0;
+        while level < LEVELS - 1 && delta >= 1 << (SLOT_BITS * (level as u32 + 1)) {
+            level += 1;
+        }
+        let slot = (target >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.link(level, slot, index);
+    }
+
+    fn link(&mut self, level: usize, slot: usize, index: u16) {
+        let head = self.heads[level][slot];
+        let node = &mut self.nodes[index as usize];
+        node.slot = (level * SLOTS + slot) as u16;
+        node.prev = NIL;
+        node.next = head;
+        if head != NIL {
+            self.nodes[head as usize].prev = index;
+        }
+        self.heads[level][slot] = index;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let (prev, next, slot) = {
+            let node = &self.nodes[index as usize];
+            (node.prev, node.next, node.slot as usize)
+        };
+        if prev == NIL {
+            self.heads[slot / SLOTS][slot % SLOTS] = next;
+        } else {
+            self.nodes[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.nodes[next as usize].prev = prev;
+        }
+    }
+
+    // スロットのリストを丸ごと外して先頭を返す
+    fn take(&mut self, level: usize, slot: usize) -> u16 {
+        core::mem::replace(&mut self.heads[level][slot], NIL)
+    }
+
+    fn alloc(&mut self) -> Option<u16> {
+        if self.free != NIL {
+            let index = self.free;
+            self.free = self.nodes[index as usize].next;
+            Some(index)
+        } else if (self.unused as usize) < CAP {
+            self.unused += 1;
+            Some(self.unused - 1)
+        } else {
+            None
+        }
+    }
+
+    // リストから外したノードを開放して値を返す
+    fn release(&mut self, index: u16) -> T {
+        let node = &mut self.nodes[index as usize];
+        let value = node.value.take().unwrap();
+        node.generation = node.generation.wrapping_add(1);
+        node.next = self.free;
+        self.free = index;
+        self.len -= 1;
+        value
+    }
+}
+
+impl<T, const CAP: usize> Default for TimerWheel<T, CAP> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Timersに登録するタイマー
+pub trait Periodic {
+    fn period(&self) -> u32;
+    fn auto_reload(&self) -> bool;
+    fn id(&self) -> &Cell<Option<TimerId>>; // ホイールの登録。Timersだけが使う
+    fn set_active(&self, active: bool);
+}
+
+struct Active<T: 'static> {
+    timer: &'static T,
+    expiry: u32,
+}
+
+impl<T> Clone for Active<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Active<T> {}
+
+// 動いているタイマー
+pub struct Timers<T: 'static, const CAP: usize> {
+    wheel: TimerWheel<Active<T>, CAP>,
+}
+
+impl<T: Periodic, const CAP: usize> Timers<T, CAP> {
+    pub const fn new() -> Self {
+        Timers {
+            wheel: TimerWheel::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.wheel.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wheel.is_empty()
+    }
+
+    // issued_atから周期を数えて登録する。動いていれば数え直す。いっぱいで登録できなければfalse
+    pub fn start(&mut self, timer: &'static T, issued_at: u32) -> bool {
+        self.cancel(timer);
+        // 空のホイールは、登録する前にコマンドを出した時刻に合わせる
+        if self.wheel.is_empty() {
+            self.wheel.set_now(issued_at);
+        }
+        self.insert(timer, issued_at.wrapping_add(timer.period()))
+    }
+
+    pub fn stop(&mut self, timer: &'static T) {
+        self.cancel(timer);
+        timer.set_active(false);
+    }
+
+    fn cancel(&mut self, timer: &'static T) {
+        if let Some(id) = timer.id().take() {
+            self.wheel.cancel(id);
+        }
+    }
+
+    fn insert(&mut self, timer: &'static T, expiry: u32) -> bool {
+        let id = self.wheel.insert(expiry, Active { timer, expiry });
+        timer.id().set(id);
+        timer.set_active(id.is_some());
+        id.is_some()
+    }
+
+    // 次に満了するまでのtick数。動いているタイマーがなければNone
+    pub fn next_timeout(&self, now: u32) -> Option<u32> {
+        let next = self.wheel.next_expiry()?;
+        Some((next.wrapping_sub(now) as i32).max(0) as u32)
+    }
+
+    // nowまでに満了したタイマーでfを呼ぶ。自動リロードは次の満了時刻で登録し直す
+    pub fn expire(&mut self, now: u32, mut f: impl FnMut(&'static T)) {
+        let mut expired = [None; CAP];
+        let mut len = 0;
+        self.wheel.advance(now, |active| {
+            expired[len] = Some(active);
+            len += 1;
+        });
+        for active in expired[..len].iter().flatten() {
+            let timer = active.timer;
+            timer.id().set(None);
+            if timer.auto_reload() {
+                // 周期がずれないように、前回の満了時刻から数える。遅れた分は続けて呼ぶ
+                let period = timer.period().max(1);
+                let mut expiry = active.expiry;
+                loop {
+                    f(timer);
+                    expiry = expiry.wrapping_add(period);
+                    if (now.wrapping_sub(expiry) as i32) < 0 {
+                        break;
+                    }
+                }
+                // 取り出したばかりなので空きはある
+                self.insert(timer, expiry);
+            } else {
+                timer.set_active(false);
+                f(timer);
+            }
+        }
+    }
+}
+
+impl<T: Periodic, const CAP: usize> Default for Timers<T, CAP> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{Periodic, TimerId, TimerWheel, Timers, MAX_DELTA};
+    use core::cell::Cell;
+    use std::boxed::Box;
+
+    // 比較用: 期限順に並べた配列。登録はO(n)
+    struct SortedList {
+        entries: Vec<(u32, u32)>, // (expiry, value)
+        now: u32,
+    }
+
+    impl SortedList {
+        fn new() -> Self {
+            SortedList {
+                entries: Vec::new(),
+                now: 0,
+            }
+        }
+
+        fn insert(&mut self, expiry: u32, value: u32) {
+            let key = expiry.wrapping_sub(self.now) as i32;
+            let i = self
+                .entries
+                .partition_point(|(e, _)| e.wrapping_sub(self.now) as i32 <= key);
+            self.entries.insert(i, (expiry, value));
+        }
+
+        fn cancel(&mut self, value: u32) -> bool {
+            let Some(i) = self.entries.iter().position(|(_, v)| *v == value) else {
+                return false;
+            };
+            self.entries.remove(i);
+            true
+        }
+
+        fn advance(&mut self, now: u32, mut f: impl FnMut(u32)) {
+            self.now = now;
+            while let Some(&(expiry, value)) = self.entries.first() {
+                if (now.wrapping_sub(expiry) as i32) < 0 {
+                    break;
+                }
+                self.entries.remove(0);
+                f(value);
+            }
+        }
+    }
+
+    // 再現可能な擬似乱数
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            self.0 >> 8
+        }
+    }
+
+    #[test]
+    fn test_insert_advance() {
+        let mut wheel: TimerWheel<u32, 8> = TimerWheel::new();
+        let mut fired = Vec::new();
+        wheel.insert(3, 3).unwrap();
+        wheel.insert(1, 1).unwrap();
+        wheel.insert(70, 70).unwrap(); // 階層1
+        wheel.insert(5000, 5000).unwrap(); // 階層2
+        assert_eq!(4, wheel.len());
+
+        assert_eq!(Some(1), wheel.next_expiry());
+        wheel.advance(2, |v| fired.push(v));
+        assert_eq!([1], fired[..]);
+        assert_eq!(Some(3), wheel.next_expiry());
+        wheel.advance(69, |v| fired.push(v));
+        assert_eq!([1, 3], fired[..]);
+        assert_eq!(Some(70), wheel.next_expiry());
+        wheel.advance(70, |v| fired.push(v));
+        assert_eq!([1, 3, 70], fired[..]);
+        wheel.advance(4999, |v| fired.push(v));
+        assert_eq!(3, fired.len());
+        wheel.advance(5000, |v| fired.push(v));
+        assert_eq!([1, 3, 70, 5000], fired[..]);
+        assert!(wheel.is_empty());
+        assert_eq!(None, wheel.next_expiry());
+
+        // 過ぎた時刻は次のtickで取り出す
+        wheel.insert(100, 100).unwrap();
+        wheel.advance(5001, |v| fired.push(v));
+        assert_eq!(Some(&100), fired.last());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut wheel: TimerWheel<u32, 2> = TimerWheel::new();
+        let a = wheel.insert(10, 1).unwrap();
+        let b = wheel.insert(10, 2).unwrap();
+        assert_eq!(None, wheel.insert(10, 3));
+        assert_eq!(Some(1), wheel.cancel(a));
+        assert_eq!(None, wheel.cancel(a));
+        // 開放したノードを再利用しても、古い番号では取り消せない
+        let c = wheel.insert(20, 3).unwrap();
+        assert_eq!(None, wheel.cancel(a));
+        let mut fired = Vec::new();
+        wheel.advance(10, |v| fired.push(v));
+        assert_eq!([2], fired[..]);
+        assert_eq!(None, wheel.cancel(b));
+        assert_eq!(Some(3), wheel.cancel(c));
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_far_future_and_wrap_around() {
+        let mut wheel: TimerWheel<u32, 4> = TimerWheel::new();
+        let mut fired = Vec::new();
+        wheel.advance(u32::MAX - 10, |_| {});
+        let start = wheel.now();
+        // MAX_DELTAより先は繰り下げを繰り返す
+        let far = start.wrapping_add(MAX_DELTA + 123);
+        wheel.insert(far, 1).unwrap();
+        wheel.insert(start.wrapping_add(20), 2).unwrap(); // カウンタが一周する
+        wheel.advance(start.wrapping_add(20), |v| fired.push(v));
+        assert_eq!([2], fired[..]);
+        wheel.advance(far.wrapping_sub(1), |v| fired.push(v));
+        assert_eq!([2], fired[..]);
+        wheel.advance(far, |v| fired.push(v));
+        assert_eq!([2, 1], fired[..]);
+    }
+
+    // ランダムな登録・取り消しで、ソート済みリストと同じtickに同じ値を取り出す
+    #[test]
+    fn test_against_sorted_list() {
+        let mut wheel: TimerWheel<u32, 256> = TimerWheel::new();
+        let mut list = SortedList::new();
+        let mut ids: Vec<(u32, TimerId)> = Vec::new();
+        let mut rng = Lcg(1);
+        let mut now = 0u32;
+        for value in 0..20_000u32 {
+            match rng.next() % 4 {
+                0 | 1 if ids.len() < 256 => {
+                    let delay = 1 + match rng.next() % 3 {
+                        0 => rng.next() % 64,
+                        1 => rng.next() % 5000,
+                        _ => rng.next() % 300_000,
+                    };
+                    let expiry = now.wrapping_add(delay);
+                    ids.push((value, wheel.insert(expiry, value).unwrap()));
+                    list.insert(expiry, value);
+                }
+                2 if !ids.is_empty() => {
+                    let (value, id) = ids.swap_remove(rng.next() as usize % ids.len());
+                    assert_eq!(list.cancel(value), wheel.cancel(id).is_some());
+                }
+                _ => {
+                    // 次の期限より先に眠りすぎない
+                    if let Some(&(expiry, _)) = list.entries.first() {
+                        let next = wheel.next_expiry().unwrap();
+                        assert!((next.wrapping_sub(now) as i32) > 0);
+                        assert!((expiry.wrapping_sub(next) as i32) >= 0);
+                    }
+                    now = now.wrapping_add(rng.next() % 2000);
+                    let (mut a, mut b) = (Vec::new(), Vec::new());
+                    wheel.advance(now, |v| a.push(v));
+                    list.advance(now, |v| b.push(v));
+                    a.sort();
+                    b.sort();
+                    assert_eq!(b, a);
+                    ids.retain(|(v, _)| !a.contains(v));
+                }
+            }
+        }
+        assert_eq!(list.entries.len(), wheel.len());
+    }
+
+    // ホストでの性能比較。cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_against_sorted_list() {
+        use std::println;
+        use std::time::Instant;
+
+        const N: usize = 4000;
+        let mut rng = Lcg(7);
+        let delays: Vec<u32> = (0..N).map(|_| 1 + rng.next() % 10_000).collect();
+
+        let mut wheel: TimerWheel<u32, N> = TimerWheel::new();
+        let start = Instant::now();
+        let ids: Vec<TimerId> = delays
+            .iter()
+            .enumerate()
+            .map(|(i, d)| wheel.insert(*d, i as u32).unwrap())
+            .collect();
+        let wheel_insert = start.elapsed();
+        let start = Instant::now();
+        for id in ids.iter().step_by(2) {
+            wheel.cancel(*id);
+        }
+        let wheel_cancel = start.elapsed();
+        let start = Instant::now();
+        let mut fired = 0;
+        for t in 1..=10_000 {
+            wheel.advance(t, |_| fired += 1);
+        }
+        let wheel_advance = start.elapsed();
+        assert_eq!(N / 2, fired);
+
+        let mut list = SortedList::new();
+        let start = Instant::now();
+        for (i, d) in delays.iter().enumerate() {
+            list.insert(*d, i as u32);
+        }
+        let list_insert = start.elapsed();
+        let start = Instant::now();
+        for i in (0..N).step_by(2) {
+            list.cancel(i as u32);
+        }
+        let list_cancel = start.elapsed();
+        let start = Instant::now();
+        let mut fired = 0;
+        for t in 1..=10_000 {
+            list.advance(t, |_| fired += 1);
+        }
+        let list_advance = start.elapsed();
+        assert_eq!(N / 2, fired);
+
+        println!("{N} timeouts      insert      cancel      advance(10000 ticks)");
+        println!("timer wheel  {wheel_insert:>10?}  {wheel_cancel:>10?}  {wheel_advance:>10?}");
+        println!("sorted list  {list_insert:>10?}  {list_cancel:>10?}  {list_advance:>10?}");
+    }
+
+    struct TestTimer {
+        period: Cell<u32>,
+        auto_reload: bool,
+        id: Cell<Option<TimerId>>,
+        active: Cell<bool>,
+    }
+
+    impl Periodic for TestTimer {
+        fn period(&self) -> u32 {
+            self.period.get()
+        }
+
+        fn auto_reload(&self) -> bool {
+            self.auto_reload
+        }
+
+        fn id(&self) -> &Cell<Option<TimerId>> {
+            &self.id
+        }
+
+        fn set_active(&self, active: bool) {
+            self.active.set(active);
+        }
+    }
+
+    fn timer(period: u32, auto_reload: bool) -> &'static TestTimer {
+        Box::leak(Box::new(TestTimer {
+            period: Cell::new(period),
+            auto_reload,
+            id: Cell::new(None),
+            active: Cell::new(false),
+        }))
+    }
+
+    // nowまでに満了した回数
+    fn expire<const CAP: usize>(timers: &mut Timers<TestTimer, CAP>, now: u32) -> usize {
+        let mut count = 0;
+        timers.expire(now, |_| count += 1);
+        count
+    }
+
+    #[test]
+    fn test_one_shot() {
+        let one_shot = timer(10, false);
+        let mut timers: Timers<TestTimer, 4> = Timers::new();
+        assert_eq!(None, timers.next_timeout(0));
+
+        assert!(timers.start(one_shot, 100));
+        assert!(one_shot.active.get());
+        // サービスタスクが遅れて処理しても、コマンドを出した時刻から数える
+        assert_eq!(Some(7), timers.next_timeout(103));
+        assert_eq!(0, expire(&mut timers, 109));
+        let mut fired = None;
+        timers.expire(110, |timer| fired = Some(timer));
+        assert!(core::ptr::eq(one_shot, fired.unwrap()));
+        assert!(!one_shot.active.get());
+        assert_eq!(0, expire(&mut timers, 200));
+        assert_eq!(None, timers.next_timeout(200));
+    }
+
+    #[test]
+    fn test_auto_reload() {
+        let periodic = timer(5, true);
+        let mut timers: Timers<TestTimer, 4> = Timers::new();
+        // カウンタのラップアラウンドをまたぐ
+        timers.start(periodic, u32::MAX - 6);
+        assert_eq!(0, expire(&mut timers, u32::MAX - 2));
+        assert_eq!(1, expire(&mut timers, u32::MAX - 1));
+        assert_eq!(0, expire(&mut timers, u32::MAX - 1));
+        assert_eq!(Some(5), timers.next_timeout(u32::MAX - 1));
+        // 遅れた分は続けて満了する
+        assert_eq!(2, expire(&mut timers, 9));
+        assert_eq!(0, expire(&mut timers, 9));
+        assert!(periodic.active.get());
+
+        periodic.period.set(20);
+        timers.start(periodic, 10);
+        assert_eq!(Some(20), timers.next_timeout(10));
+
+        timers.stop(periodic);
+        assert!(!periodic.active.get());
+        assert_eq!(0, expire(&mut timers, 100));
+        assert_eq!(None, timers.next_timeout(100));
+    }
+
+    #[test]
+    fn test_reset() {
+        let reset = timer(10, false);
+        let mut timers: Timers<TestTimer, 4> = Timers::new();
+        timers.start(reset, 0);
+        timers.start(reset, 8);
+        // 同じタイマーは登録し直す
+        assert_eq!(1, timers.len());
+        assert_eq!(0, expire(&mut timers, 10));
+        assert_eq!(1, expire(&mut timers, 18));
+    }
+
+    #[test]
+    fn test_many_timers() {
+        let list = [timer(3, false), timer(3, true), timer(100_000, false)];
+        let mut timers: Timers<TestTimer, 4> = Timers::new();
+        for timer in list {
+            timers.start(timer, 0);
+        }
+        assert_eq!(2, expire(&mut timers, 3));
+        assert_eq!(1, expire(&mut timers, 6));
+        // 遠いタイマーまで飛ばしても、自動リロードは遅れた分を呼ぶ
+        assert_eq!(33_331, expire(&mut timers, 99_999));
+        let mut fired = None;
+        timers.expire(100_000, |timer| {
+            if !timer.auto_reload {
+                fired = Some(timer)
+            }
+        });
+        assert!(core::ptr::eq(list[2], fired.unwrap()));
+        assert!(!list[2].active.get());
+        assert!(list[1].active.get());
+    }
+
+    #[test]
+    fn test_timers_full() {
+        let list = [timer(1, false), timer(2, false), timer(3, false)];
+        let mut timers: Timers<TestTimer, 2> = Timers::new();
+        assert!(timers.start(list[0], 0));
+        assert!(timers.start(list[1], 0));
+        assert!(!timers.start(list[2], 0));
+        assert!(!list[2].active.get());
+        // 止めれば空く
+        timers.stop(list[0]);
+        assert!(timers.start(list[2], 0));
+        assert_eq!(2, expire(&mut timers, 3));
+        assert!(timers.is_empty());
+    }
+}
@@ -0,0 +1,699 @@
+// TLSF(Two-Level Segregated Fit)アロケータ。global_allocatorの中身
+// 空きブロックを大きさで2段階(2のべき乗と、その間を16分割)に分類したリストで管理する。
+// 確保はビットマップで空きのあるリストを探すだけ、開放は前後の空きブロックと結合するだけなので、
+// どちらもヒープの状態によらず一定時間で終わる。
+// 確保したタスクの使用量(TaskHeapStats)の数え方も持つ。ロックやタスクには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/tlsf.rs
+
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+
+const WORD: usize = size_of::<usize>();
+const ALIGN: usize = 2 * WORD; // ブロックの大きさと、返すポインタのアライメント
+const ALIGN_SHIFT: u32 = ALIGN.trailing_zeros();
+const HEADER: usize = 2 * WORD; // prev_phys, size
+const MIN_BLOCK: usize = HEADER + 2 * WORD; // 空きリストのポインタが入る大きさ
+const SL_BITS: u32 = 4;
+const SL_COUNT: usize = 1 << SL_BITS;
+const FL_SHIFT: u32 = SL_BITS + ALIGN_SHIFT; // これより小さいブロックは、fl=0にALIGNごとに分類する
+const FL_COUNT: usize = (usize::BITS - FL_SHIFT + 1) as usize;
+
+const FREE: usize = 1; // Block::sizeの下位ビット
+
+#[repr(C)]
+struct Block {
+    prev_phys: *mut Block, // 物理的に前のブロック。先頭ならnull
+    size: usize,           // ヘッダを含む大きさ | FREE
+    next_free: *mut Block, // ここから下は空きブロックのときだけ使う
+    prev_free: *mut Block,
+}
+
+pub struct Heap {
+    fl_bitmap: usize,
+    sl_bitmap: [u32; FL_COUNT],
+    heads: [[*mut Block; SL_COUNT]; FL_COUNT],
+    start: usize,
+    end: usize,  // 0ならinit()されていない
+    used: usize, // 確保中のブロックの大きさ(ヘッダを含む)の合計
+    peak: usize,
+    count: usize,    // 確保中のブロックの数
+    failures: usize, // 確保できなかった回数
+}
+
+unsafe impl Send for Heap {}
+
+// 大きさはバイト数。ブロックのヘッダを含む
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub peak: usize,         // usedの最大値
+    pub largest_free: usize, // 一番大きい空きブロック。確保できる大きさの目安
+    pub count: usize,        // 確保中の数
+    pub failures: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomCause {
+    HeapExhausted, // ヒープに空きがない
+    QuotaExceeded, // 確保しようとしたタスクの上限を超える
+}
+
+// タスクごとの使用量。大きさはブロックのヘッダを含む
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskHeapStats {
+    pub quota: Option<usize>, // 使用量の上限。Noneなら上限なし
+    pub used: usize,
+    pub peak: usize,
+    pub count: usize,
+    pub failures: usize, // 上限を超えて確保できなかった回数
+}
+
+impl TaskHeapStats {
+    pub const fn new() -> Self {
+        TaskHeapStats {
+            quota: None,
+            used: 0,
+            peak: 0,
+            count: 0,
+            failures: 0,
+        }
+    }
+
+    // 上限を超えなければsizeを使用量に加える
+    pub fn charge(&mut self, size: usize) -> bool {
+        let used = self.used + size;
+        if self.quota.is_some_and(|quota| used > quota) {
+            self.failures += 1;
+            return false;
+        }
+        self.used = used;
+        self.peak = self.peak.max(used);
+        self.count += 1;
+        true
+    }
+
+    pub fn refund(&mut self, size: usize) {
+        self.used -= size;
+        self.count -= 1;
+    }
+}
+
+const fn align_up(value: usize, align: usize) -> Option<usize> {
+    match value.checked_add(align - 1) {
+        Some(v) => Some(v & !(align - 1)),
+        None => None,
+    }
+}
+
+// layoutを確保するブロックの大きさ(ヘッダを含む)。余りを切り出せなければ、実際のブロックはこれより大きい
+fn block_size(layout: Layout) -> Option<usize> {
+    let payload = layout.size().max(MIN_BLOCK - HEADER);
+    align_up(payload, ALIGN)?.checked_add(HEADER)
+}
+
+// ブロックの大きさが入るリスト
+fn mapping(size: usize) -> (usize, usize) {
+    if size < 1 << FL_SHIFT {
+        (0, size >> ALIGN_SHIFT)
+    } else {
+        let f = usize::BITS - 1 - size.leading_zeros();
+        let sl = (size >> (f - SL_BITS)) & (SL_COUNT - 1);
+        ((f - FL_SHIFT + 1) as usize, sl)
+    }
+}
+
+// 確保するときに探し始めるリスト。どのブロックもsize以上になるように切り上げる
+fn mapping_search(size: usize) -> Option<(usize, usize)> {
+    if size < 1 << FL_SHIFT {
+        return Some(mapping(size));
+    }
+    let f = usize::BITS - 1 - size.leading_zeros();
+    Some(mapping(size.checked_add((1 << (f - SL_BITS)) - 1)?))
+}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Heap {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            heads: [[ptr::null_mut(); SL_COUNT]; FL_COUNT],
+            start: 0,
+            end: 0,
+            used: 0,
+            peak: 0,
+            count: 0,
+            failures: 0,
+        }
+    }
+
+    /// # Safety
+    /// start..start+sizeは、他に使われないメモリでなければならない。1度だけ呼ぶ
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        let begin = align_up(start, ALIGN).unwrap();
+        let end = (start + size) & !(ALIGN - 1);
+        assert!(end > begin && end - begin >= MIN_BLOCK, "heap too small");
+        self.start = begin;
+        self.end = end;
+        let block = begin as *mut Block;
+        (*block).prev_phys = ptr::null_mut();
+        (*block).size = (end - begin) | FREE;
+        self.insert(block);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.end != 0
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let Some(ptr) = self.alloc_block(layout) else {
+            self.failures += 1;
+            return None;
+        };
+        let block = (ptr.as_ptr() as usize - HEADER) as *mut Block;
+        self.used += unsafe { self.size(block) };
+        self.peak = self.peak.max(self.used);
+        self.count += 1;
+        Some(ptr)
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let size = self.end - self.start;
+        HeapStats {
+            size,
+            used: self.used,
+            free: size - self.used,
+            peak: self.peak,
+            largest_free: self.largest_free(),
+            count: self.count,
+            failures: self.failures,
+        }
+    }
+
+    // 一番大きいクラスのリストだけを調べる
+    fn largest_free(&self) -> usize {
+        if self.fl_bitmap == 0 {
+            return 0;
+        }
+        let fl = (usize::BITS - 1 - self.fl_bitmap.leading_zeros()) as usize;
+        let sl = (u32::BITS - 1 - self.sl_bitmap[fl].leading_zeros()) as usize;
+        let mut largest = 0;
+        let mut block = self.heads[fl][sl];
+        while !block.is_null() {
+            unsafe {
+                largest = largest.max(self.size(block));
+                block = (*block).next_free;
+            }
+        }
+        largest
+    }
+
+    fn alloc_block(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let size = block_size(layout)?;
+        let align = layout.align();
+        // アライメントが大きければ、先頭を切り出せるだけ余分に探す
+        let request = if align <= ALIGN {
+            size
+        } else {
+            size.checked_add(align)?.checked_add(MIN_BLOCK)?
+        };
+        let (fl, sl) = mapping_search(request)?;
+        let (fl, sl) = self.find_suitable(fl, sl)?;
+        unsafe {
+            let mut block = self.heads[fl][sl];
+            self.remove(block);
+            if align > ALIGN {
+                let payload = block as usize + HEADER;
+                let mut aligned = align_up(payload, align)?;
+                if aligned != payload && aligned - payload < MIN_BLOCK {
+                    aligned = align_up(payload + MIN_BLOCK, align)?;
+                }
+                if aligned != payload {
+                    let rest = self.split_off(block, aligned - payload);
+                    (*block).size |= FREE;
+                    self.insert(block);
+                    block = rest;
+                }
+            }
+            self.trim(block, size);
+            (*block).size &= !FREE;
+            NonNull::new((block as usize + HEADER) as *mut u8)
+        }
+    }
+
+    /// # Safety
+    /// ptrはこのHeapのalloc()が返して、まだ開放していないポインタでなければならない
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>) {
+        let mut block = (ptr.as_ptr() as usize - HEADER) as *mut Block;
+        self.used -= self.size(block);
+        self.count -= 1;
+        (*block).size |= FREE;
+        let prev = (*block).prev_phys;
+        if !prev.is_null() && self.is_free(prev) {
+            self.remove(prev);
+            self.merge(prev, block);
+            block = prev;
+        }
+        let next = self.next_phys(block);
+        if !next.is_null() && self.is_free(next) {
+            self.remove(next);
+            self.merge(block, next);
+        }
+        self.insert(block);
+    }
+
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<(usize, usize)> {
+        let sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
+        let fl_map = self.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1).unwrap_or(0);
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    unsafe fn size(&self, block: *mut Block) -> usize {
+        (*block).size & !FREE
+    }
+
+    unsafe fn is_free(&self, block: *mut Block) -> bool {
+        (*block).size & FREE != 0
+    }
+
+    unsafe fn next_phys(&self, block: *mut Block) -> *mut Block {
+        let next = block as usize + self.size(block);
+        if next >= self.end {
+            ptr::null_mut()
+        } else {
+            next as *mut Block
+        }
+    }
+
+    unsafe fn insert(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping(self.size(block));
+        let head = self.heads[fl][sl];
+        (*block).next_free = head;
+        (*block).prev_free = ptr::null_mut();
+        if !head.is_null() {
+            (*head).prev_free = block;
+        }
+        self.heads[fl][sl] = block;
+        self.sl_bitmap[fl] |= 1 << sl;
+        self.fl_bitmap |= 1 << fl;
+    }
+
+    unsafe fn remove(&mut self, block: *mut Block) {
+        let (fl, sl) = mapping(self.size(block));
+        let (prev, next) = ((*block).prev_free, (*block).next_free);
+        if !prev.is_null() {
+            (*prev).next_free = next;
+        }
+        if !next.is_null() {
+            (*next).prev_free = prev;
+        }
+        if self.heads[fl][sl] == block {
+            self.heads[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+    }
+
+    // blockを先頭からsizeのところで2つに分けて、後ろ(使用中)を返す
+    unsafe fn split_off(&mut self, block: *mut Block, size: usize) -> *mut Block {
+        let rest = (block as usize + size) as *mut Block;
+        (*rest).prev_phys = block;
+        (*rest).size = self.size(block) - size;
+        (*block).size = size | ((*block).size & FREE);
+        let next = self.next_phys(rest);
+        if !next.is_null() {
+            (*next).prev_phys = rest;
+        }
+        rest
+    }
+
+    // 確保するブロックをsizeに縮めて、残りを空きリストに戻す
+    unsafe fn trim(&mut self, block: *mut Block, size: usize) {
+        if self.size(block) - size >= MIN_BLOCK {
+            let rest = self.split_off(block, size);
+            (*rest).size |= FREE;
+            self.insert(rest);
+        }
+    }
+
+    // 物理的に隣り合うblockとnextを1つにする
+    unsafe fn merge(&mut self, block: *mut Block, next: *mut Block) {
+        (*block).size += self.size(next);
+        let after = self.next_phys(block);
+        if !after.is_null() {
+            (*after).prev_phys = block;
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 確保したもの(タスク)を、ブロックの末尾に書いておく。開放したときに、どれの使用量から引くかがわかる
+// Oは何でもよい(ポインタを書いておくだけで、参照しない)
+type Tag<O> = *mut O;
+
+// ownerの使用量を操作する
+pub type WithUsage<'a, O> = &'a dyn Fn(NonNull<O>, &mut dyn FnMut(&mut TaskHeapStats));
+
+// ownerの使用量に数えて確保し、ownerをブロックの末尾に書いておく。Noneならどれにも数えない
+pub fn alloc_owned<O>(
+    heap: &mut Heap,
+    layout: Layout,
+    owner: Option<NonNull<O>>,
+    usage: WithUsage<O>,
+) -> Result<NonNull<u8>, OomCause> {
+    let Some((full, offset, size)) = owned_layout::<O>(layout) else {
+        return Err(OomCause::HeapExhausted);
+    };
+    if let Some(owner) = owner {
+        let mut charged = false;
+        usage(owner, &mut |stats| charged = stats.charge(size));
+        if !charged {
+            return Err(OomCause::QuotaExceeded);
+        }
+    }
+    let Some(ptr) = heap.alloc(full) else {
+        if let Some(owner) = owner {
+            usage(owner, &mut |stats| stats.refund(size));
+        }
+        return Err(OomCause::HeapExhausted);
+    };
+    let tag = owner.map_or(ptr::null_mut(), NonNull::as_ptr);
+    unsafe {
+        ptr.as_ptr()
+            .add(offset)
+            .cast::<Tag<O>>()
+            .write_unaligned(tag)
+    };
+    Ok(ptr)
+}
+
+// 開放したものではなく、ブロックの末尾に書いておいたものの使用量から引く
+/// # Safety
+/// ptrはalloc_owned(heap, layout, ..)が返して、まだ開放していないポインタでなければならない
+pub unsafe fn dealloc_owned<O>(
+    heap: &mut Heap,
+    ptr: NonNull<u8>,
+    layout: Layout,
+    usage: WithUsage<O>,
+) {
+    let (_, offset, size) = owned_layout::<O>(layout).unwrap();
+    let tag = ptr.as_ptr().add(offset).cast::<Tag<O>>().read_unaligned();
+    if let Some(owner) = NonNull::new(tag) {
+        usage(owner, &mut |stats| stats.refund(size));
+    }
+    heap.dealloc(ptr);
+}
+
+// 末尾にTagを足したLayoutと、Tagの位置、使用量に数える大きさ
+fn owned_layout<O>(layout: Layout) -> Option<(Layout, usize, usize)> {
+    let (full, offset) = layout.extend(Layout::new::<Tag<O>>()).ok()?;
+    Some((full, offset, block_size(full)?))
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::{
+        alloc_owned, block_size, dealloc_owned, mapping, owned_layout, Block, Heap, OomCause,
+        TaskHeapStats, ALIGN, FL_COUNT, HEADER, MIN_BLOCK, SL_COUNT,
+    };
+    use core::alloc::Layout;
+    use core::cell::RefCell;
+    use core::ptr::NonNull;
+    use std::vec;
+    use std::vec::Vec;
+
+    // 全てのブロックと空きリストを調べる。空きの合計を返す
+    fn check(heap: &Heap) -> usize {
+        let mut free = 0;
+        let mut free_blocks = 0;
+        let mut block = heap.start as *mut Block;
+        let mut prev: *mut Block = core::ptr::null_mut();
+        unsafe {
+            while !block.is_null() {
+                assert_eq!(prev, (*block).prev_phys);
+                let size = heap.size(block);
+                assert!(size >= MIN_BLOCK && size.is_multiple_of(ALIGN));
+                if heap.is_free(block) {
+                    // 隣り合う空きブロックは結合されている
+                    assert!(prev.is_null() || !heap.is_free(prev));
+                    free += size;
+                    free_blocks += 1;
+                }
+                prev = block;
+                block = heap.next_phys(block);
+            }
+            assert_eq!(heap.end, prev as usize + heap.size(prev));
+
+            let mut listed = 0;
+            for fl in 0..FL_COUNT {
+                assert_eq!(heap.fl_bitmap & (1 << fl) != 0, heap.sl_bitmap[fl] != 0);
+                for sl in 0..SL_COUNT {
+                    let mut b = heap.heads[fl][sl];
+                    assert_eq!(heap.sl_bitmap[fl] & (1 << sl) != 0, !b.is_null());
+                    while !b.is_null() {
+                        assert!(heap.is_free(b));
+                        assert_eq!((fl, sl), mapping(heap.size(b)));
+                        listed += 1;
+                        b = (*b).next_free;
+                    }
+                }
+            }
+            assert_eq!(free_blocks, listed);
+        }
+        free
+    }
+
+    fn new_heap(memory: &mut [u128]) -> Heap {
+        let mut heap = Heap::new();
+        unsafe { heap.init(memory.as_mut_ptr() as usize, size_of_val(memory)) };
+        heap
+    }
+
+    #[test]
+    fn test_alloc_dealloc_coalesce() {
+        let mut memory = vec![0u128; 256];
+        let mut heap = new_heap(&mut memory);
+        let total = check(&heap);
+        assert_eq!(4096, total);
+
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let a = heap.alloc(layout).unwrap();
+        let b = heap.alloc(layout).unwrap();
+        let c = heap.alloc(layout).unwrap();
+        assert_eq!(0, a.as_ptr() as usize % ALIGN);
+        check(&heap);
+        // 真ん中、前、後ろの順に開放すると、1つの空きブロックに戻る
+        unsafe {
+            heap.dealloc(b);
+            check(&heap);
+            heap.dealloc(a);
+            check(&heap);
+            heap.dealloc(c);
+        }
+        assert_eq!(total, check(&heap));
+
+        let stats = heap.stats();
+        assert_eq!(0, stats.used);
+        assert_eq!(3 * (100usize.next_multiple_of(ALIGN) + HEADER), stats.peak);
+        assert_eq!(total, stats.largest_free);
+
+        // 全体を1つで確保できる
+        let all = Layout::from_size_align(total - HEADER, 1).unwrap();
+        let p = heap.alloc(all).unwrap();
+        assert!(heap.alloc(Layout::new::<u8>()).is_none());
+        let stats = heap.stats();
+        assert_eq!(
+            (total, 0, 0, 1, 1),
+            (
+                stats.used,
+                stats.free,
+                stats.largest_free,
+                stats.count,
+                stats.failures
+            )
+        );
+        unsafe { heap.dealloc(p) };
+        assert_eq!(total, check(&heap));
+    }
+
+    #[test]
+    fn test_large_alignment() {
+        let mut memory = vec![0u128; 256];
+        let mut heap = new_heap(&mut memory);
+        let small = heap.alloc(Layout::new::<u8>()).unwrap();
+        let aligned = heap
+            .alloc(Layout::from_size_align(64, 256).unwrap())
+            .unwrap();
+        assert_eq!(0, aligned.as_ptr() as usize % 256);
+        check(&heap);
+        unsafe {
+            heap.dealloc(aligned);
+            heap.dealloc(small);
+        }
+        assert_eq!(4096, check(&heap));
+    }
+
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize
+        }
+    }
+
+    // ランダムな確保と開放を、確保中の領域のモデルと比べる
+    #[test]
+    fn test_against_model() {
+        let mut memory = vec![0u128; 4096];
+        let mut heap = new_heap(&mut memory);
+        let total = check(&heap);
+        let (heap_start, heap_end) = (heap.start, heap.end);
+        let mut rng = Lcg(0x1234_5678);
+        // (ポインタ, Layout, 書き込んだ値)
+        let mut live: Vec<(NonNull<u8>, Layout, u8)> = Vec::new();
+        for i in 0..20_000 {
+            if rng.next() % 10 < 6 {
+                let size = if rng.next().is_multiple_of(16) {
+                    rng.next() % 8192
+                } else {
+                    rng.next() % 256
+                };
+                let align = 1 << (rng.next() % 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let Some(p) = heap.alloc(layout) else {
+                    continue;
+                };
+                let addr = p.as_ptr() as usize;
+                assert_eq!(0, addr % align);
+                assert!(addr >= heap_start && addr + size <= heap_end);
+                for (q, l, _) in &live {
+                    let other = q.as_ptr() as usize;
+                    assert!(addr + size <= other || other + l.size() <= addr);
+                }
+                let fill = i as u8;
+                unsafe { core::ptr::write_bytes(p.as_ptr(), fill, size) };
+                live.push((p, layout, fill));
+            } else if !live.is_empty() {
+                let (p, layout, fill) = live.swap_remove(rng.next() % live.len());
+                let data = unsafe { core::slice::from_raw_parts(p.as_ptr(), layout.size()) };
+                assert!(data.iter().all(|b| *b == fill));
+                unsafe { heap.dealloc(p) };
+            }
+            if i % 64 == 0 {
+                let stats = heap.stats();
+                assert_eq!(check(&heap), stats.free);
+                assert_eq!(live.len(), stats.count);
+                assert!(stats.largest_free <= stats.free && stats.used <= stats.peak);
+            }
+        }
+        for (p, _, _) in live.drain(..) {
+            unsafe { heap.dealloc(p) };
+        }
+        assert_eq!(total, check(&heap));
+    }
+
+    #[test]
+    fn test_task_quota() {
+        let mut usage = TaskHeapStats::new();
+        assert!(usage.charge(1000));
+        usage.refund(1000);
+
+        let size = block_size(Layout::from_size_align(40, 4).unwrap()).unwrap();
+        usage.quota = Some(2 * size);
+        assert!(usage.charge(size));
+        assert!(usage.charge(size));
+        assert!(!usage.charge(size)); // 上限を超える
+        assert_eq!(2 * size, usage.used);
+        assert_eq!(2, usage.count);
+        assert_eq!(1, usage.failures);
+
+        usage.refund(size);
+        assert!(usage.charge(size));
+        assert_eq!(1000, usage.peak);
+    }
+
+    // 確保したタスクをブロックに書いておき、別のタスクが開放しても確保したタスクに返す
+    #[test]
+    fn test_owner_tag() {
+        let mut memory = vec![0u128; 256];
+        let mut heap = new_heap(&mut memory);
+        // タスクのポインタは番号として使うだけ(参照しない)
+        let task = |id: usize| NonNull::new((id * 64) as *mut u32).unwrap();
+        let usages = RefCell::new([TaskHeapStats::new(); 2]);
+        let usage = |task: NonNull<u32>, f: &mut dyn FnMut(&mut TaskHeapStats)| {
+            f(&mut usages.borrow_mut()[task.as_ptr() as usize / 64 - 1])
+        };
+        let layout = Layout::from_size_align(30, 2).unwrap();
+        let (_, _, size) = owned_layout::<u32>(layout).unwrap();
+
+        let a = alloc_owned(&mut heap, layout, Some(task(1)), &usage).unwrap();
+        let b = alloc_owned(&mut heap, layout, Some(task(1)), &usage).unwrap();
+        let kernel = alloc_owned::<u32>(&mut heap, layout, None, &usage).unwrap();
+        assert_eq!((2 * size, 2), {
+            let u = usages.borrow()[0];
+            (u.used, u.count)
+        });
+        assert_eq!(0, usages.borrow()[1].used);
+        assert_eq!(3, heap.stats().count);
+
+        // どのタスクが開放しても(開放する側は渡さない)、確保したタスクの使用量から引く
+        unsafe {
+            dealloc_owned(&mut heap, a, layout, &usage);
+            dealloc_owned(&mut heap, kernel, layout, &usage);
+        }
+        assert_eq!((size, 1), {
+            let u = usages.borrow()[0];
+            (u.used, u.count)
+        });
+        assert_eq!(0, usages.borrow()[1].used);
+
+        // 上限を超えると、ヒープから確保しない
+        usages.borrow_mut()[1].quota = Some(size);
+        let c = alloc_owned(&mut heap, layout, Some(task(2)), &usage).unwrap();
+        assert_eq!(
+            Err(OomCause::QuotaExceeded),
+            alloc_owned(&mut heap, layout, Some(task(2)), &usage)
+        );
+        assert_eq!(2, heap.stats().count);
+        assert_eq!(1, usages.borrow()[1].failures);
+
+        // ヒープが足りなければ、数えた分を戻す
+        let huge = Layout::from_size_align(8192, 2).unwrap();
+        assert_eq!(
+            Err(OomCause::HeapExhausted),
+            alloc_owned(&mut heap, huge, Some(task(1)), &usage)
+        );
+        assert_eq!(size, usages.borrow()[0].used);
+
+        unsafe {
+            dealloc_owned(&mut heap, b, layout, &usage);
+            dealloc_owned(&mut heap, c, layout, &usage);
+        }
+        assert_eq!(0, usages.borrow()[0].used + usages.borrow()[1].used);
+        assert_eq!(4096, check(&heap));
+    }
+}
@@ -0,0 +1,72 @@
+// タスクが渡したアドレスの確認。IPCのシステムコールの引数に使う
+// タスクには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/user_memory.rs
+
+use core::ops::Range;
+
+// タスクが読み書きできるメモリ(SRAM0-5)と、読めるメモリ(XIPのフラッシュ)
+pub const RAM: Range<usize> = 0x2000_0000..0x2004_2000;
+pub const FLASH: Range<usize> = 0x1000_0000..0x1100_0000;
+
+// [addr, addr + len)がregionに収まるか。オーバーフローするものは収まらない
+pub fn within(region: &Range<usize>, addr: usize, len: usize) -> bool {
+    addr >= region.start && addr.checked_add(len).is_some_and(|end| end <= region.end)
+}
+
+// 大きさsize、アラインメントalignの引数が、タスクのスタックに収まるか
+pub fn argument_ok(stack: &Range<usize>, addr: usize, size: usize, align: usize) -> bool {
+    addr.is_multiple_of(align) && within(stack, addr, size)
+}
+
+// 送信するバッファはRAMかフラッシュ、受信するバッファはRAMになければならない。長さ0ならどこでもよい
+pub fn buffers_ok(send: usize, send_len: usize, recv: usize, recv_len: usize) -> bool {
+    let readable = send_len == 0 || within(&RAM, send, send_len) || within(&FLASH, send, send_len);
+    let writable = recv_len == 0 || within(&RAM, recv, recv_len);
+    readable && writable
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_within() {
+        let region = 0x100..0x200;
+        assert!(within(&region, 0x100, 0x100));
+        assert!(within(&region, 0x1ff, 1));
+        assert!(!within(&region, 0xff, 1));
+        assert!(!within(&region, 0x1ff, 2));
+        assert!(!within(&region, usize::MAX, 2));
+    }
+
+    #[test]
+    fn test_argument() {
+        let stack = 0x2000_1000..0x2000_1100;
+        assert!(argument_ok(&stack, 0x2000_1000, 0x40, 4));
+        assert!(argument_ok(&stack, 0x2000_10c0, 0x40, 4));
+        // はみ出す
+        assert!(!argument_ok(&stack, 0x2000_10c4, 0x40, 4));
+        assert!(!argument_ok(&stack, 0x2000_0ffc, 0x40, 4));
+        // アラインメントが合わない
+        assert!(!argument_ok(&stack, 0x2000_1002, 0x40, 4));
+    }
+
+    #[test]
+    fn test_buffers() {
+        let dangling = 1;
+        assert!(buffers_ok(dangling, 0, dangling, 0));
+        // フラッシュは読めるが、書けない
+        assert!(buffers_ok(FLASH.start, 16, RAM.start, 16));
+        assert!(!buffers_ok(RAM.start, 16, FLASH.start, 16));
+        // RAMの終わりをまたぐ
+        assert!(buffers_ok(RAM.end - 16, 16, RAM.end - 16, 16));
+        assert!(!buffers_ok(RAM.end - 16, 17, dangling, 0));
+        assert!(!buffers_ok(dangling, 0, RAM.end - 16, 17));
+        // ペリフェラルやオーバーフローするアドレス
+        assert!(!buffers_ok(0x4000_0000, 4, dangling, 0));
+        assert!(!buffers_ok(dangling, 0, usize::MAX - 1, 4));
+        // フラッシュとRAMをまたぐものは読めない
+        assert!(!buffers_ok(FLASH.end - 4, 8, dangling, 0));
+    }
+}
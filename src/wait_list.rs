@@ -0,0 +1,100 @@
+// 同期オブジェクト(Queueなど)でブロックしているタスクのリスト
+// 起こされたタスクはスケジューラが次に巡回したときに実行可能になる
+// 登録できるタスクはN個(既定はWAIT_LIST_LEN)。同時に待つタスクがそれより多い同期オブジェクトは、
+// WaitList<N>でNを大きくする。いっぱいのときに待とうとしたタスクは、待ち状態にならずにポーリングする(警告を出す)
+
+use crate::syscall;
+use crate::task::TaskHandle;
+use crate::waiters::Waiters;
+use defmt::warn;
+
+pub const WAIT_LIST_LEN: usize = 8;
+
+pub struct WaitList<const N: usize = WAIT_LIST_LEN> {
+    waiters: Waiters<TaskHandle, N>,
+}
+
+impl<const N: usize> WaitList<N> {
+    pub const fn new() -> Self {
+        WaitList {
+            waiters: Waiters::new(),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    // 末尾に登録する。いっぱいならfalse
+    pub fn push(&mut self, task: TaskHandle) -> bool {
+        self.waiters.push(task)
+    }
+
+    pub fn contains(&self, task: TaskHandle) -> bool {
+        self.waiters.contains(task)
+    }
+
+    // 一番長く待っているタスク
+    pub fn first(&self) -> Option<TaskHandle> {
+        self.waiters.first()
+    }
+
+    // タイムアウトしたタスクは自分で登録を削除する
+    pub fn remove(&mut self, task: TaskHandle) {
+        self.waiters.remove(task);
+    }
+
+    // 先頭(一番長く待っている)タスクを起こす
+    pub fn wake_one(&mut self) -> Option<TaskHandle> {
+        let task = self.waiters.pop_first()?;
+        task.wake();
+        Some(task)
+    }
+
+    pub fn wake_all(&mut self) {
+        while self.wake_one().is_some() {}
+    }
+
+    // 実行中のタスクを登録して待ち状態にする。
+    // 同期オブジェクトのロックを保持したまま呼び、ロックを開放してから suspend() を呼ぶ。
+    // ロックを開放してから切り替わるまでの間に起こされても、起床は失われない。
+    // いっぱいで登録できなければfalse。タスクは実行可能のままなので、呼び出し側はポーリングになる
+    pub fn block_current(&mut self, deadline: Option<u32>) -> bool {
+        let Some(task) = TaskHandle::current() else {
+            return false;
+        };
+        if !self.push(task) {
+            warn!("wait list is full ({}), task {:x} polls", N, task.id());
+            return false;
+        }
+        task.block(deadline);
+        true
+    }
+
+    pub fn remove_current(&mut self) {
+        if let Some(task) = TaskHandle::current() {
+            self.remove(task);
+        }
+    }
+}
+
+impl<const N: usize> Default for WaitList<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// カーネルに戻って他のタスクを実行する。
+// タスク以外(カーネル初期化中など)から呼ばれた場合はスピンするだけ。
+// 待ちリストがいっぱいで登録できなかった場合も、タスクは実行可能のままなのでポーリングになる。
+pub fn suspend() {
+    if TaskHandle::current().is_some() {
+        syscall::back_to_kernel();
+    } else {
+        core::hint::spin_loop();
+    }
+}
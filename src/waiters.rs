@@ -0,0 +1,112 @@
+// 待っている順に並べた、重複のない固定長のリスト。WaitListの中身
+// タスクには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/waiters.rs
+
+pub struct Waiters<T, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+}
+
+impl<T: Copy + PartialEq, const N: usize> Waiters<T, N> {
+    pub const fn new() -> Self {
+        Waiters {
+            items: [None; N],
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // 末尾に登録する。登録済みなら何もしない。いっぱいならfalse
+    pub fn push(&mut self, item: T) -> bool {
+        if self.contains(item) {
+            return true;
+        }
+        if self.len == N {
+            return false;
+        }
+        self.items[self.len] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    pub fn contains(&self, item: T) -> bool {
+        self.items[..self.len].contains(&Some(item))
+    }
+
+    // 一番長く待っているもの
+    pub fn first(&self) -> Option<T> {
+        self.items[..self.len].first().copied().flatten()
+    }
+
+    // 途中のものを削除しても、残りの順番は変わらない
+    pub fn remove(&mut self, item: T) -> bool {
+        let Some(i) = self.items[..self.len].iter().position(|w| *w == Some(item)) else {
+            return false;
+        };
+        self.items.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+        self.items[self.len] = None;
+        true
+    }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        let item = self.first()?;
+        self.remove(item);
+        Some(item)
+    }
+}
+
+impl<T: Copy + PartialEq, const N: usize> Default for Waiters<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Waiters;
+
+    #[test]
+    fn test_capacity() {
+        let mut list: Waiters<u32, 2> = Waiters::new();
+        assert_eq!(2, list.capacity());
+        assert!(list.push(1));
+        assert!(list.push(1)); // 登録済み
+        assert_eq!(1, list.len());
+        assert!(list.push(2));
+        assert!(!list.push(3));
+        assert!(list.remove(1));
+        assert!(!list.remove(1));
+        assert!(list.push(3));
+        assert_eq!(Some(2), list.first());
+    }
+
+    #[test]
+    fn test_order() {
+        let mut list: Waiters<u32, 4> = Waiters::new();
+        for i in 1..=4 {
+            assert!(list.push(i));
+        }
+        // 途中を削除しても順番は変わらない
+        assert!(list.remove(2));
+        assert!(list.push(5));
+        assert_eq!(Some(1), list.pop_first());
+        assert_eq!(Some(3), list.pop_first());
+        assert_eq!(Some(4), list.pop_first());
+        assert_eq!(Some(5), list.pop_first());
+        assert_eq!(None, list.pop_first());
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+// 待ち状態のタスクを実行可能に戻すかの判定。Task::exec()が使う
+// タスクには依存しないので、単体でテストできる
+//
+// ❯ rustc --test src/wake.rs
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    Stay,    // 待ち続ける
+    Woken,   // 待ちリストや通知などから起こされた
+    Expired, // 期限が来た。IPCを待っていれば取り消す
+}
+
+// woken: wake()された、expired: 期限が来た、ipc: IPCの完了を待っている
+// IPCは完了するとタスクの状態を戻してから起こすので、起こされてもまだIPCを待っているなら
+// 通知など別の理由で起こされている。svcの戻り値はまだIPC_TIMEOUTなので、期限まで待ち続ける
+pub fn check(woken: bool, expired: bool, ipc: bool) -> Wake {
+    if expired {
+        Wake::Expired
+    } else if woken && !ipc {
+        Wake::Woken
+    } else {
+        Wake::Stay
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, Wake};
+
+    #[test]
+    fn test_check() {
+        assert_eq!(Wake::Stay, check(false, false, false));
+        assert_eq!(Wake::Woken, check(true, false, false));
+        assert_eq!(Wake::Expired, check(false, true, false));
+        assert_eq!(Wake::Expired, check(true, true, false));
+    }
+
+    // receive(None)で待っているタスクにnotify()しても、受信するまで待ち続ける
+    #[test]
+    fn test_notify_during_receive() {
+        assert_eq!(Wake::Stay, check(true, false, true));
+        // 期限が来れば取り消す
+        assert_eq!(Wake::Expired, check(true, true, true));
+        // 送信されると、IPCは完了してから起こす
+        assert_eq!(Wake::Woken, check(true, false, false));
+    }
+}